@@ -0,0 +1,36 @@
+// Copyright 2024 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::{env, fs, io::Write, path::Path};
+
+use flate2::{write::GzEncoder, Compression};
+
+// Static assets that get served pre-compressed. The gzipped copies land in OUT_DIR
+// so they can be embedded with include_bytes!.
+const GZIPPED_ASSETS: [&str; 3] = ["htmx.min.js", "plotly-2.27.0.min.js", "lib.mjs"];
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR not set");
+    for asset in GZIPPED_ASSETS {
+        let src = Path::new("static").join(asset);
+        println!("cargo:rerun-if-changed={}", src.display());
+        let content = fs::read(&src).expect("Unable to read static asset");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder
+            .write_all(&content)
+            .expect("Unable to compress static asset");
+        let compressed = encoder.finish().expect("Unable to compress static asset");
+        fs::write(Path::new(&out_dir).join(format!("{}.gz", asset)), compressed)
+            .expect("Unable to write compressed static asset");
+    }
+}
@@ -11,26 +11,95 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use std::path::Path;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use chrono::prelude::*;
 use chrono::Duration;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use serde_yaml;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use crate::query::LogQueryResult;
 use crate::query::{
-    loki_to_sample, prom_to_samples, LokiConn, PromQueryConn, MetricsQueryResult, QueryType,
+    loki_to_sample, prom_to_samples, DataPoint, GapFillMethod, LokiConn, MetricsQueryResult,
+    PromQueryConn, QueryBudget, QueryEstimate, QueryType, SourceAuth, SourceType, TimePrecision,
+    DEFAULT_QUERY_TIMEOUT_SECONDS, MAX_POINTS_PER_SERIES,
 };
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct PlotConfig {
+    /// Either a javascript template literal wrapped in backticks that the browser evaluates or
+    /// a `{label}` format like `{job} - {instance}` rendered on the server into `name`.
     name_format: Option<String>,
+    /// The legend name rendered from a `{label}` name_format. Set on the server.
+    #[serde(skip_deserializing)]
+    name: Option<String>,
     fill: Option<FillTypes>,
     yaxis: Option<String>,
+    /// Multiplier applied to every value. e.g. 0.001 to show milliseconds as seconds.
+    scale: Option<f64>,
+    /// Added to every value after scaling.
+    offset: Option<f64>,
+    /// Query the native histogram samples and draw them as a heatmap. The query has to return
+    /// native histograms, e.g. `sum(rate(http_request_duration_seconds[5m]))`. Scale and
+    /// offset don't apply to the bucket boundaries.
+    #[serde(default)]
+    histogram: bool,
+    /// Set from the graph and subplot `clamp_negative` options.
+    #[serde(skip)]
+    clamp_negative: bool,
+}
+
+impl PlotConfig {
+    /// Applies the configured unit scaling to a value. Defaults to the identity.
+    pub fn scale_value(&self, value: f64) -> f64 {
+        value * self.scale.unwrap_or(1.0) + self.offset.unwrap_or(0.0)
+    }
+
+    /// True if negative values should be replaced with zero.
+    pub fn clamp_negative(&self) -> bool {
+        self.clamp_negative
+    }
+
+    /// True if the plot queries native histogram samples.
+    pub fn histogram(&self) -> bool {
+        self.histogram
+    }
+
+    pub fn set_name_format(&mut self, name_format: &str) {
+        self.name_format = Some(name_format.to_string());
+    }
+
+    /// Renders a `{label}` name_format against the series labels into `name`. Labels the
+    /// series doesn't have render as an empty string. Javascript formats are left for the
+    /// browser.
+    pub fn resolve_name(&mut self, labels: &HashMap<String, String>) {
+        self.name = match self.name_format.as_deref() {
+            Some(format) if !format.starts_with('`') => Some(render_label_format(format, labels)),
+            _ => None,
+        };
+    }
+}
+
+/// Substitutes each `{label}` in the format with the label's value. A `{` without a closing
+/// `}` is kept as is.
+fn render_label_format(format: &str, labels: &HashMap<String, String>) -> String {
+    let mut name = String::with_capacity(format.len());
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        let label = &rest[start + 1..start + len];
+        name.push_str(labels.get(label).map(String::as_str).unwrap_or_default());
+        rest = &rest[start + len + 1..];
+    }
+    name.push_str(rest);
+    name
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -84,27 +153,175 @@ pub struct AxisDefinition {
     plot_type: Option<AxisType>,
 }
 
-#[derive(Deserialize, Debug)]
+/// A marker for a point in time on a graph. e.g. a deploy.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Annotation {
+    pub timestamp: DateTime<Utc>,
+    pub label: String,
+    pub color: Option<String>,
+}
+
+/// An annotation declared in the config for every graph on a dashboard. e.g. a maintenance
+/// window. The timestamp is RFC3339, `now`, or relative to now like `now-2h`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct StaticAnnotation {
+    pub timestamp: String,
+    #[serde(alias = "label")]
+    pub text: String,
+    pub color: Option<String>,
+}
+
+impl StaticAnnotation {
+    /// Resolves the timestamp against the current time.
+    pub fn to_annotation(&self) -> Option<Annotation> {
+        Some(Annotation {
+            timestamp: parse_relative_time(&self.timestamp)?,
+            label: self.text.clone(),
+            color: self.color.clone(),
+        })
+    }
+}
+
+/// Parses `now`, `now-<duration>` like `now-2h`, or an RFC3339 timestamp.
+fn parse_relative_time(timestamp: &str) -> Option<DateTime<Utc>> {
+    if timestamp == "now" {
+        return Some(Utc::now());
+    }
+    if let Some(ago) = timestamp.strip_prefix("now-") {
+        return Some(Utc::now() - duration_from_string(ago)?);
+    }
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|t| t.to_utc())
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct GraphSpan {
     // serialized with https://datatracker.ietf.org/doc/html/rfc3339 and special handling for 'now'
+    // and 'now-<duration>'
     pub end: String,
+    /// Start of a fixed window like `2024-02-09T09:00:00Z`. Parsed like end. When set it takes
+    /// the place of duration.
+    pub start: Option<String>,
+    /// Required unless start is set.
+    #[serde(default)]
     pub duration: String,
     pub step_duration: String,
 }
 
+impl GraphSpan {
+    /// The length of the span. Either end - start or the duration. None if they are invalid or
+    /// start isn't before end.
+    fn span_duration(&self) -> Option<Duration> {
+        match self.start {
+            Some(ref start) => {
+                let duration = parse_relative_time(&self.end)? - parse_relative_time(start)?;
+                (duration > Duration::zero()).then_some(duration)
+            }
+            None => duration_from_string(&self.duration),
+        }
+    }
+}
+
+/// Splits a long range graph into a coarse query over the whole span and a fine query over the
+/// most recent part of it.
+#[derive(Deserialize, Debug, Clone)]
+pub struct OverviewDetail {
+    /// Step for the query over the whole span.
+    pub overview_step: String,
+    /// How much of the end of the span to query at the detail step.
+    pub detail_duration: String,
+    pub detail_step: String,
+}
+
+/// A named set of label filters that can be applied to every graph on a dashboard at once.
+#[derive(Deserialize)]
+pub struct FilterView {
+    pub name: String,
+    pub filters: HashMap<String, String>,
+}
+
 #[derive(Deserialize)]
 pub struct Dashboard {
     pub title: String,
     pub graphs: Option<Vec<Graph>>,
     pub logs: Option<Vec<LogStream>>,
     pub span: Option<GraphSpan>,
+    /// How long each upstream query gets. e.g. 10s. Graphs and log streams can override it.
+    /// Defaults to DEFAULT_QUERY_TIMEOUT_SECONDS.
+    pub timeout: Option<String>,
+    pub views: Option<Vec<FilterView>>,
+    /// Position in the dashboard menu. Dashboards without one follow in config order.
+    #[serde(alias = "order")]
+    pub menu_order: Option<i32>,
+    /// Hidden dashboards are left out of the menu but can still be reached by url.
+    #[serde(default)]
+    pub hidden: bool,
+    /// Annotations shown on every graph in the dashboard.
+    #[serde(default)]
+    pub annotations: Vec<StaticAnnotation>,
+    /// Always use the configured step. Otherwise steps are raised to stay under the
+    /// --max-points. Strict range queries over MAX_POINTS_PER_SERIES points fail.
+    #[serde(default)]
+    pub strict_step: bool,
+    /// Seconds to cache identical prometheus query results for. 0 disables caching. Defaults to
+    /// the --cache-seconds flag.
+    pub cache_seconds: Option<u64>,
+    /// Seconds past cache_seconds that a cached result is still served while it gets refreshed
+    /// in the background. Defaults to 0 which always waits for a fresh result.
+    pub stale_seconds: Option<u64>,
+    /// Most upstream graph and log queries per minute across everyone viewing the dashboard.
+    /// Queries past it get a 429 unless a cached result can be served. 0 means no limit.
+    /// Defaults to the --query-budget flag.
+    pub query_budget: Option<u32>,
+    /// Queries used of the query_budget in the current minute.
+    #[serde(skip)]
+    pub budget: QueryBudget,
+    /// d3 tick formats by graph unit. These override and add to DEFAULT_UNIT_TICK_FORMATS.
+    #[serde(default)]
+    pub unit_tick_formats: HashMap<String, String>,
+    /// Stylesheet loaded after site.css. Either a url or a file path relative to the config file.
+    pub custom_css: Option<String>,
+    /// Contents of a file based custom_css. Loaded at startup.
+    #[serde(skip)]
+    pub custom_css_content: Option<String>,
+    /// Javascript module whose default export processes graph payloads before they render.
+    /// The path is relative to the --assets-dir and has to stay inside it.
+    pub custom_js: Option<PathBuf>,
+    /// Contents of the custom_js module. Loaded at startup.
+    #[serde(skip)]
+    pub custom_js_content: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct SubPlot {
+    /// The source queried when the request doesn't select one of the named sources.
     pub source: String,
+    /// Named alternatives to source selected with the `source` query param. e.g.
+    /// `{prod: http://prom-prod:9090, stage: http://prom-stage:9090}`
+    #[serde(default)]
+    pub sources: HashMap<String, String>,
+    /// Credentials sent with every query to the source.
+    pub auth: Option<SourceAuth>,
+    /// Tenant sent as the X-Scope-OrgID header for multi-tenant sources like Mimir and Loki.
+    pub org_id: Option<String>,
+    /// Overrides the graph's query timeout for this plot.
+    pub timeout: Option<String>,
     pub query: String,
     pub config: PlotConfig,
+    /// How far back the source keeps data. Only used as a hint during validation.
+    pub retention: Option<String>,
+    #[serde(default)]
+    pub source_type: SourceType,
+    /// Thanos only. Deduplicate replicated series. Defaults to true.
+    pub dedup: Option<bool>,
+    /// Thanos only. Return partial results when some stores are unavailable. Defaults to false.
+    pub partial_response: Option<bool>,
+    /// Overrides the graph's clamp_negative for this plot.
+    pub clamp_negative: Option<bool>,
+    /// Overrides the graph's query_type for this plot. e.g. a Scalar threshold drawn over a
+    /// Range graph.
+    pub query_type: Option<QueryType>,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -115,6 +332,178 @@ pub enum Orientation {
     Vertical,
 }
 
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub enum ComparisonOp {
+    #[serde(rename = "gt")]
+    Gt,
+    #[serde(rename = "lt")]
+    Lt,
+    #[serde(rename = "gte")]
+    Gte,
+    #[serde(rename = "lte")]
+    Lte,
+}
+
+impl ComparisonOp {
+    /// Returns true if `left <op> right` holds. NaN never matches.
+    pub fn compare(&self, left: f64, right: f64) -> bool {
+        match self {
+            ComparisonOp::Gt => left > right,
+            ComparisonOp::Lt => left < right,
+            ComparisonOp::Gte => left >= right,
+            ComparisonOp::Lte => left <= right,
+        }
+    }
+}
+
+/// Colors a scalar value when it compares true against `value`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Threshold {
+    pub op: ComparisonOp,
+    pub value: f64,
+    pub color: String,
+}
+
+/// Returns the color of the first threshold the value matches in config order.
+pub fn threshold_color(thresholds: &[Threshold], value: f64) -> Option<&str> {
+    thresholds
+        .iter()
+        .find(|t| t.op.compare(value, t.value))
+        .map(|t| t.color.as_str())
+}
+
+/// How a graph's results get shown.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub enum ChartType {
+    #[default]
+    #[serde(rename = "plot")]
+    Plot,
+    /// A sortable table with one row per series.
+    #[serde(rename = "table")]
+    Table,
+}
+
+/// An error budget computed from counters of good and total events.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Slo {
+    pub source: String,
+    /// Credentials sent with the slo queries.
+    pub auth: Option<SourceAuth>,
+    /// Tenant sent as the X-Scope-OrgID header with the slo queries.
+    pub org_id: Option<String>,
+    /// Selector for a counter of good events. e.g. `http_requests_total{code!~"5.."}`
+    pub good_query: String,
+    /// Selector for a counter of all events. e.g. `http_requests_total`
+    pub total_query: String,
+    /// Target fraction of good events. e.g. 0.999
+    pub objective: f64,
+    /// Prometheus duration the budget covers. e.g. 30d
+    pub window: String,
+}
+
+/// The state of an Slo's error budget over its window. A burn rate of 1 uses up exactly the
+/// budget by the end of the window.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SloStatus {
+    pub error_ratio: f64,
+    pub burn_rate: f64,
+    pub budget_remaining: f64,
+    /// Color from the graph's thresholds compared against the burn rate.
+    pub color: Option<String>,
+}
+
+/// Display transforms applied to a graph's results on the server.
+#[derive(Deserialize, Clone, Debug)]
+pub enum Transform {
+    /// Running total across the span.
+    #[serde(rename = "cumulative")]
+    Cumulative,
+}
+
+/// Reduces a series to a single value for comparisons.
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+pub enum SeriesReducer {
+    #[default]
+    #[serde(rename = "last")]
+    Last,
+    #[serde(rename = "max")]
+    Max,
+    #[serde(rename = "mean")]
+    Mean,
+}
+
+impl SeriesReducer {
+    /// Returns None for an empty series.
+    pub fn reduce(&self, points: &[DataPoint]) -> Option<f64> {
+        let last = points.last()?.value();
+        let values = points.iter().map(|p| p.value());
+        Some(match self {
+            SeriesReducer::Last => last,
+            SeriesReducer::Max => values.fold(f64::NEG_INFINITY, f64::max),
+            SeriesReducer::Mean => values.sum::<f64>() / points.len() as f64,
+        })
+    }
+}
+
+/// Keeps only the series whose reduced value compares true against `value`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ValueFilter {
+    pub op: ComparisonOp,
+    pub value: f64,
+    /// Defaults to the last value.
+    #[serde(default)]
+    pub by: SeriesReducer,
+}
+
+impl ValueFilter {
+    pub fn matches(&self, points: &[DataPoint]) -> bool {
+        self.by
+            .reduce(points)
+            .map(|v| self.op.compare(v, self.value))
+            .unwrap_or(false)
+    }
+}
+
+/// Fills short gaps in Range results left by missed scrapes.
+#[derive(Deserialize, Clone, Debug)]
+pub struct GapFill {
+    /// The widest gap, in query steps, that gets filled.
+    pub max_steps: usize,
+    #[serde(default)]
+    pub method: GapFillMethod,
+}
+
+/// Drops series that would only clutter the legend.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Prune {
+    /// Drop series where every value is NaN.
+    #[serde(default)]
+    pub drop_all_nan: bool,
+    /// Drop series where every value that isn't NaN is the same. e.g. a flat zero.
+    #[serde(default)]
+    pub drop_constant: bool,
+}
+
+impl Prune {
+    pub fn keeps(&self, points: &[DataPoint]) -> bool {
+        let mut values = points.iter().map(|p| p.value()).filter(|v| !v.is_nan());
+        match values.next() {
+            // Empty series are left for the fallback and value filter to deal with.
+            None => points.is_empty() || !self.drop_all_nan,
+            Some(first) => !self.drop_constant || values.any(|v| v != first),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub enum ScalarDisplay {
+    #[default]
+    #[serde(rename = "number")]
+    Number,
+    #[serde(rename = "gauge")]
+    Gauge,
+}
+
 // NOTE(zapher): These two structs look repetitive but we haven't hit the rule of three yet.
 // If we do then it might be time to restructure them a bit.
 #[derive(Deserialize)]
@@ -124,18 +513,131 @@ pub struct Graph {
     pub yaxes: Vec<AxisDefinition>,
     pub plots: Vec<SubPlot>,
     pub span: Option<GraphSpan>,
+    /// Overrides the dashboard's query timeout.
+    pub timeout: Option<String>,
+    /// Defaults to Range when omitted.
+    #[serde(default)]
     pub query_type: QueryType,
     pub d3_tick_format: Option<String>,
+    /// Unit of the values. e.g. bytes. Picks a default d3_tick_format when there isn't one.
+    pub unit: Option<String>,
+    /// Show the results as a plot (the default) or as a table.
+    #[serde(default)]
+    pub chart_type: ChartType,
+    /// Label columns for table charts. Defaults to every label.
+    #[serde(default)]
+    pub table_labels: Vec<String>,
+    /// How Scalar query results get shown. Defaults to a plain number.
+    #[serde(default)]
+    pub scalar_display: ScalarDisplay,
+    pub gauge_min: Option<f64>,
+    pub gauge_max: Option<f64>,
+    /// Query long Range spans as a coarse overview plus a detailed recent window.
+    pub overview_detail: Option<OverviewDetail>,
+    /// Include this graph's current values in the /api/key-metrics feed.
+    #[serde(default)]
+    pub key_metric: bool,
+    /// When a Range query returns no data show the current value from an instant query instead.
+    #[serde(default)]
+    pub fallback_instant: bool,
+    /// Show an error budget panel computed from these counters.
+    pub slo: Option<Slo>,
+    /// Fill gaps of a few missing samples. Longer gaps stay as breaks.
+    pub gap_fill: Option<GapFill>,
+    /// Replace negative values with zero. e.g. for the spikes counter resets cause in rates.
+    #[serde(default)]
+    pub clamp_negative: bool,
+    /// Drop all NaN or constant series from the results.
+    pub prune: Option<Prune>,
+    /// Transform applied to every series before it is returned.
+    pub transform: Option<Transform>,
+    /// Only show series whose value passes this filter.
+    pub value_filter: Option<ValueFilter>,
+    /// Overrides the dashboard's cache_seconds. 0 turns caching off for real time panels.
+    pub cache_seconds: Option<u64>,
+    /// Downsample series with more points than this. Spikes are kept. See
+    /// `MetricsQueryResult::downsample`.
+    pub max_points: Option<usize>,
+    /// Colors for Scalar results. The first matching threshold wins.
+    #[serde(default)]
+    pub thresholds: Vec<Threshold>,
+    /// RFC3339 times to evaluate a Scalar query at. The values come back as a series with one
+    /// point per snapshot instead of a single current value.
+    pub snapshots: Option<Vec<DateTime<Utc>>>,
+    /// Labels whose values get masked before the results are sent to the client.
+    #[serde(default)]
+    pub redact_labels: Vec<String>,
+    /// Truncate label values longer than this many characters. Filters and legends use the
+    /// truncated values.
+    pub max_label_len: Option<usize>,
+    /// YAML or JSON file with a list of annotations to mark on this graph. Relative paths are
+    /// resolved against the directory of the config file.
+    pub annotations_file: Option<PathBuf>,
+    #[serde(skip)]
+    pub annotations: Vec<Annotation>,
+    /// Stop polling for new data while the browser tab is hidden.
+    #[serde(default = "default_pause_when_hidden")]
+    pub pause_when_hidden: bool,
 }
 
 #[derive(Deserialize)]
 pub struct LogStream {
     pub title: String,
     pub source: String,
+    /// Credentials sent with every query to the source.
+    pub auth: Option<SourceAuth>,
+    /// Tenant sent as the X-Scope-OrgID header for multi-tenant sources like Mimir and Loki.
+    pub org_id: Option<String>,
     pub query: String,
     pub span: Option<GraphSpan>,
+    /// Overrides the dashboard's query timeout.
+    pub timeout: Option<String>,
     pub limit: Option<usize>,
+    /// Defaults to Range when omitted.
+    #[serde(default)]
     pub query_type: QueryType,
+    /// How far back the source keeps data. Only used as a hint during validation.
+    pub retention: Option<String>,
+    /// Labels whose values get masked before the results are sent to the client.
+    #[serde(default)]
+    pub redact_labels: Vec<String>,
+    /// Truncate label values longer than this many characters.
+    pub max_label_len: Option<usize>,
+    /// Fail the query on malformed results instead of skipping them.
+    #[serde(default)]
+    pub strict_parse: bool,
+    /// strftime style format for log line times. Defaults to RFC3339.
+    pub time_format: Option<String>,
+    /// Subsecond precision of the default RFC3339 times. Ignored when time_format is set.
+    #[serde(default)]
+    pub time_precision: TimePrecision,
+    /// Path of the loki api under the source url. Defaults to /loki/api/v1.
+    pub api_prefix: Option<String>,
+    /// Log streams with the same tab group render as tabs in one section. Only the selected
+    /// tab queries for data.
+    pub tab_group: Option<String>,
+    /// Merge the lines of every stream into one list sorted newest first with each line
+    /// tagged by its stream's labels. Defaults to grouping the lines by stream.
+    #[serde(default)]
+    pub merge_streams: bool,
+    /// Stop polling for new data while the browser tab is hidden.
+    #[serde(default = "default_pause_when_hidden")]
+    pub pause_when_hidden: bool,
+}
+
+fn default_pause_when_hidden() -> bool {
+    true
+}
+
+/// The results of a graph's queries along with anything the viewer should be warned about.
+#[derive(Debug)]
+pub struct GraphData {
+    pub plots: Vec<MetricsQueryResult>,
+    pub warnings: Vec<String>,
+    /// Set when a plot fell back to an instant query. See `Graph::fallback_instant`.
+    pub fallback: bool,
+    /// The largest step the range queries actually used. None when nothing was a range query.
+    pub step_seconds: Option<i64>,
 }
 
 pub async fn prom_query_data<'a>(
@@ -143,30 +645,367 @@ pub async fn prom_query_data<'a>(
     dash: &Dashboard,
     query_span: Option<GraphSpan>,
     filters: &Option<HashMap<&'a str, &'a str>>,
-) -> Result<Vec<MetricsQueryResult>> {
-    let connections = graph.get_query_connections(&dash.span, &query_span, filters);
-    let mut data = Vec::new();
-    for conn in connections {
-        data.push(prom_to_samples(
-            conn.get_results().await?.data().clone(),
-            conn.meta,
-        ));
+    source: Option<&str>,
+    scope: &[String],
+    origin: &str,
+) -> Result<GraphData> {
+    let connections =
+        graph.get_query_connections(&dash.span, &query_span, filters, source, origin);
+    // All the plots are queried at once. join_all keeps the results in plot order which the
+    // yaxes and legends depend on.
+    let results = futures::future::try_join_all(
+        connections
+            .into_iter()
+            .zip(graph.plots.iter())
+            .map(|(conn, plot)| {
+                let conn = conn
+                    .with_scope(scope)
+                    .with_strict_step(dash.strict_step)
+                    .with_timeout(query_timeout(&[&plot.timeout, &graph.timeout, &dash.timeout]))
+                    .with_cache_seconds(graph.cache_seconds.or(dash.cache_seconds))
+                    .with_stale_seconds(dash.stale_seconds)
+                    .with_budget(dash.query_budget());
+                plot_query_data(graph, conn)
+            }),
+    )
+    .await?;
+    let mut data = GraphData {
+        plots: Vec::with_capacity(results.len()),
+        warnings: Vec::new(),
+        fallback: false,
+        step_seconds: None,
+    };
+    for result in results {
+        data.plots.extend(result.plots);
+        data.warnings.extend(result.warnings);
+        data.fallback |= result.fallback;
+        data.step_seconds = data.step_seconds.max(result.step_seconds);
     }
     Ok(data)
 }
 
+/// Queries and post processes a single plot of the graph.
+async fn plot_query_data(graph: &Graph, conn: PromQueryConn<'_>) -> Result<GraphData> {
+    let mut warnings = Vec::new();
+    let mut fallback = false;
+    let step_seconds = conn.step_seconds();
+    let range = matches!(conn.query_type(), QueryType::Range);
+    if conn.meta.histogram() {
+        // Histograms are drawn as they come back. The transforms only apply to plain values.
+        let mut plot = conn.get_histograms().await?;
+        plot.redact(&graph.redact_labels);
+        if let Some(max) = graph.max_label_len {
+            plot.truncate_labels(max);
+        }
+        return Ok(GraphData {
+            plots: vec![plot],
+            warnings,
+            fallback,
+            step_seconds: range.then_some(step_seconds),
+        });
+    }
+    let mut plot = match (conn.query_type(), &graph.snapshots) {
+        (QueryType::Scalar, Some(times)) => conn.get_snapshots(times).await?,
+        _ => prom_to_samples(conn.get_results().await?.data().clone(), conn.meta.clone()),
+    };
+    if graph.fallback_instant && range && plot.is_empty() {
+        debug!("Range query returned no data. Falling back to an instant query");
+        let conn = conn.with_query_type(QueryType::Scalar);
+        plot = prom_to_samples(conn.get_results().await?.data().clone(), conn.meta)
+            .into_series();
+        fallback = true;
+    }
+    if let Some(ref gap_fill) = graph.gap_fill {
+        if range && !fallback {
+            plot.fill_gaps(step_seconds, gap_fill.max_steps, gap_fill.method);
+        }
+    }
+    if let Some(ref prune) = graph.prune {
+        let dropped = plot.retain_series(|points| prune.keeps(points));
+        if dropped > 0 {
+            warnings.push(format!("{} all NaN or constant series pruned", dropped));
+        }
+    }
+    if let Some(Transform::Cumulative) = graph.transform {
+        plot.cumulative();
+    }
+    if let Some(ref filter) = graph.value_filter {
+        let dropped = plot.retain_series(|points| filter.matches(points));
+        if dropped > 0 {
+            warnings.push(format!("{} series hidden by the value filter", dropped));
+        }
+    }
+    if let Some(max_points) = graph.max_points {
+        plot.downsample(max_points);
+    }
+    plot.redact(&graph.redact_labels);
+    if let Some(max) = graph.max_label_len {
+        plot.truncate_labels(max);
+    }
+    plot.apply_thresholds(&graph.thresholds);
+    let ranged = range && !fallback;
+    Ok(GraphData {
+        plots: vec![plot],
+        warnings,
+        fallback,
+        step_seconds: ranged.then_some(step_seconds),
+    })
+}
+
+/// Sums the values of an instant query.
+async fn slo_count(slo: &Slo, query: &str, origin: &str) -> Result<f64> {
+    let conn = PromQueryConn::new(&slo.source, query, QueryType::Scalar, PlotConfig::default())
+        .with_auth(slo.auth.as_ref())
+        .with_org_id(slo.org_id.as_deref())
+        .with_origin(origin);
+    match prom_to_samples(conn.get_results().await?.data().clone(), conn.meta) {
+        MetricsQueryResult::Scalar(v) => Ok(v.iter().map(|(_, _, point)| point.value()).sum()),
+        MetricsQueryResult::Series(_) | MetricsQueryResult::Histogram(_) => Err(anyhow::anyhow!(
+            "Slo query returned a range result: {}",
+            query
+        )),
+    }
+}
+
+/// Computes the graph's Slo status if it has one.
+pub async fn slo_data(graph: &Graph, origin: &str) -> Result<Option<SloStatus>> {
+    let slo = match graph.slo {
+        Some(ref slo) => slo,
+        None => return Ok(None),
+    };
+    let good_query = format!("sum(increase({}[{}]))", slo.good_query, slo.window);
+    let total_query = format!("sum(increase({}[{}]))", slo.total_query, slo.window);
+    let (good, total) = futures::try_join!(
+        slo_count(slo, &good_query, origin),
+        slo_count(slo, &total_query, origin),
+    )?;
+    let error_ratio = if total > 0.0 { 1.0 - good / total } else { 0.0 };
+    let burn_rate = error_ratio / (1.0 - slo.objective);
+    Ok(Some(SloStatus {
+        error_ratio,
+        burn_rate,
+        budget_remaining: 1.0 - burn_rate,
+        color: threshold_color(&graph.thresholds, burn_rate).map(str::to_string),
+    }))
+}
+
+/// The current value of one series of a key metric graph.
+#[derive(Serialize, Debug)]
+pub struct KeyMetric {
+    pub dashboard: String,
+    pub graph: String,
+    pub value: f64,
+    pub labels: HashMap<String, String>,
+}
+
+/// Most key metric graphs queried at once.
+pub const KEY_METRIC_CONCURRENCY: usize = 8;
+/// How long a key metric graph's queries get before they are skipped.
+pub const KEY_METRIC_TIMEOUT_SECONDS: u64 = 10;
+
+async fn graph_key_metrics(
+    dash: &Dashboard,
+    graph: &Graph,
+    scope: &[String],
+    origin: &str,
+) -> Result<Vec<KeyMetric>> {
+    let mut metrics = Vec::new();
+    let connections = graph.get_query_connections(&dash.span, &None, &None, None, origin);
+    for (conn, plot) in connections.into_iter().zip(graph.plots.iter()) {
+        // Key metrics are always the current value so use an instant query.
+        let conn = conn
+            .with_query_type(QueryType::Scalar)
+            .with_scope(scope)
+            .with_timeout(query_timeout(&[&plot.timeout, &graph.timeout, &dash.timeout]));
+        let mut plot = prom_to_samples(conn.get_results().await?.data().clone(), conn.meta);
+        plot.redact(&graph.redact_labels);
+        let values = match plot {
+            MetricsQueryResult::Scalar(v) => v
+                .into_iter()
+                .map(|(labels, _, point)| (labels, point.value()))
+                .collect::<Vec<_>>(),
+            MetricsQueryResult::Series(v) => v
+                .into_iter()
+                .filter_map(|(labels, _, points)| Some((labels, points.last()?.value())))
+                .collect(),
+            MetricsQueryResult::Histogram(v) => v
+                .into_iter()
+                .filter_map(|(labels, _, points)| Some((labels, points.last()?.count)))
+                .collect(),
+        };
+        metrics.extend(values.into_iter().map(|(labels, value)| KeyMetric {
+            dashboard: dash.title.clone(),
+            graph: graph.title.clone(),
+            value,
+            labels,
+        }));
+    }
+    Ok(metrics)
+}
+
+async fn key_metric_task(
+    (dash_idx, graph_idx, dash, graph, scope): (usize, usize, &Dashboard, &Graph, &[String]),
+) -> Vec<KeyMetric> {
+    let origin = format!("key-metrics dashboard={} graph={}", dash_idx, graph_idx);
+    let timeout = std::time::Duration::from_secs(KEY_METRIC_TIMEOUT_SECONDS);
+    match tokio::time::timeout(timeout, graph_key_metrics(dash, graph, scope, &origin)).await {
+        Ok(Ok(metrics)) => metrics,
+        Ok(Err(err)) => {
+            error!(dashboard = dash_idx, graph = graph_idx, ?err, "Key metric query failed");
+            Vec::new()
+        }
+        Err(_) => {
+            error!(dashboard = dash_idx, graph = graph_idx, "Key metric query timed out");
+            Vec::new()
+        }
+    }
+}
+
+/// Queries the current value of every `key_metric` graph across all the dashboards. Graphs
+/// whose queries fail or time out are logged and left out. With a scope, graphs that can't be
+/// scoped are left out too.
+pub async fn key_metric_data(dashboards: &[Dashboard], scope: &[String]) -> Vec<KeyMetric> {
+    let mut graphs = Vec::new();
+    for (dash_idx, dash) in dashboards.iter().enumerate() {
+        for (graph_idx, graph) in dash.graphs.iter().flatten().enumerate() {
+            if !graph.key_metric {
+                continue;
+            }
+            if !scope.is_empty() && !graph.scopable() {
+                debug!(dashboard = dash_idx, graph = graph_idx, "Skipping unscopable key metric");
+                continue;
+            }
+            graphs.push((dash_idx, graph_idx, dash, graph, scope));
+        }
+    }
+    let results = futures::stream::iter(graphs)
+        .map(key_metric_task)
+        .buffered(KEY_METRIC_CONCURRENCY)
+        .collect::<Vec<Vec<KeyMetric>>>()
+        .await;
+    results.into_iter().flatten().collect()
+}
+
+/// How long the readiness probe waits on each source.
+pub const READY_TIMEOUT_SECONDS: u64 = 2;
+
+/// Reachability of one source for the readiness probe.
+#[derive(Serialize, Debug)]
+pub struct SourceHealth {
+    pub source: String,
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The health check url and credentials of every distinct source across all the dashboards
+/// keyed by source. Plot `sources` alternatives are included and named `@source` references
+/// are already resolved to their urls at load. Prometheus and Thanos serve `/-/healthy` and
+/// Loki serves `/ready`.
+fn health_checks(dashboards: &[Dashboard]) -> BTreeMap<&str, (String, Option<&SourceAuth>)> {
+    let mut checks = BTreeMap::new();
+    let prom_url = |source: &str| format!("{}/-/healthy", source.trim_end_matches('/'));
+    for dash in dashboards {
+        for graph in dash.graphs.iter().flatten() {
+            for plot in graph.plots.iter() {
+                checks.insert(plot.source.as_str(), (prom_url(&plot.source), plot.auth.as_ref()));
+                // The alternatives selected with the `source` query param use the plot's auth.
+                for source in plot.sources.values() {
+                    checks.insert(source.as_str(), (prom_url(source), plot.auth.as_ref()));
+                }
+            }
+            if let Some(ref slo) = graph.slo {
+                checks.insert(slo.source.as_str(), (prom_url(&slo.source), slo.auth.as_ref()));
+            }
+        }
+        for log in dash.logs.iter().flatten() {
+            let url = format!("{}/ready", log.source.trim_end_matches('/'));
+            checks.insert(log.source.as_str(), (url, log.auth.as_ref()));
+        }
+    }
+    checks
+}
+
+async fn check_source_health(source: &str, url: &str, auth: Option<&SourceAuth>) -> SourceHealth {
+    let timeout = std::time::Duration::from_secs(READY_TIMEOUT_SECONDS);
+    let check = async {
+        let mut req = crate::query::http_client()?.get(url).timeout(timeout);
+        if let Some(auth) = auth {
+            req = req.header(reqwest::header::AUTHORIZATION, auth.header_value()?);
+        }
+        req.send().await?.error_for_status()?;
+        Ok::<(), anyhow::Error>(())
+    };
+    let result = check.await;
+    if let Err(ref err) = result {
+        warn!(source, ?err, "Source failed its health check");
+    }
+    SourceHealth {
+        source: source.to_string(),
+        healthy: result.is_ok(),
+        error: result.err().map(|err| err.to_string()),
+    }
+}
+
+/// Checks every distinct source across all the dashboards concurrently. Each check gets
+/// READY_TIMEOUT_SECONDS so a slow source can't hang the probe.
+pub async fn source_health(dashboards: &[Dashboard]) -> Vec<SourceHealth> {
+    let checks = health_checks(dashboards);
+    futures::future::join_all(
+        checks
+            .iter()
+            .map(|(source, (url, auth))| check_source_health(source, url, *auth)),
+    )
+    .await
+}
+
+pub async fn prom_estimate_data<'a>(
+    graph: &Graph,
+    dash: &Dashboard,
+    query_span: Option<GraphSpan>,
+    filters: &Option<HashMap<&'a str, &'a str>>,
+    source: Option<&str>,
+    scope: &[String],
+    origin: &str,
+) -> Result<Vec<QueryEstimate>> {
+    let connections =
+        graph.get_query_connections(&dash.span, &query_span, filters, source, origin);
+    let mut estimates = Vec::new();
+    for (conn, plot) in connections.into_iter().zip(graph.plots.iter()) {
+        let conn = conn
+            .with_scope(scope)
+            .with_strict_step(dash.strict_step)
+            .with_timeout(query_timeout(&[&plot.timeout, &graph.timeout, &dash.timeout]))
+            .with_budget(dash.query_budget());
+        estimates.push(conn.get_estimate().await?);
+    }
+    Ok(estimates)
+}
+
 pub async fn loki_query_data(
     stream: &LogStream,
     dash: &Dashboard,
     query_span: Option<GraphSpan>,
+    origin: &str,
 ) -> Result<LogQueryResult> {
-    let conn = stream.get_query_connection(&dash.span, &query_span);
+    let conn = stream
+        .get_query_connection(&dash.span, &query_span, origin)
+        .with_timeout(query_timeout(&[&stream.timeout, &dash.timeout]))
+        .with_budget(dash.query_budget());
     let response = conn.get_results().await?;
     if response.status == "success" {
-        Ok(loki_to_sample(response.data))
+        let mut lines = loki_to_sample(response.data, stream.strict_parse)?;
+        if stream.merge_streams {
+            lines = lines.merge_streams();
+        }
+        lines.redact(&stream.redact_labels);
+        if let Some(max) = stream.max_label_len {
+            lines.truncate_labels(max);
+        }
+        lines.format_times(stream.time_format.as_deref(), stream.time_precision);
+        Ok(lines)
     } else {
-        // TODO(jwall): Better error handling than this
-        panic!("Loki query status: {}", response.status)
+        Err(anyhow::anyhow!("Loki query status: {}", response.status))
     }
 }
 
@@ -189,12 +1028,21 @@ fn duration_from_string(duration_string: &str) -> Option<Duration> {
     }
 }
 
+/// The first valid timeout from the overrides in order or DEFAULT_QUERY_TIMEOUT_SECONDS.
+fn query_timeout(timeouts: &[&Option<String>]) -> std::time::Duration {
+    timeouts
+        .iter()
+        .filter_map(|timeout| timeout.as_deref().and_then(duration_from_string))
+        .find_map(|timeout| timeout.to_std().ok())
+        .unwrap_or(std::time::Duration::from_secs(DEFAULT_QUERY_TIMEOUT_SECONDS))
+}
+
 fn graph_span_to_tuple(span: &Option<GraphSpan>) -> Option<(DateTime<Utc>, Duration, Duration)> {
     if span.is_none() {
         return None;
     }
     let span = span.as_ref().unwrap();
-    let duration = match duration_from_string(&span.duration) {
+    let duration = match span.span_duration() {
         Some(d) => d,
         None => {
             error!("Invalid query duration not assigning span to to graph query");
@@ -208,55 +1056,488 @@ fn graph_span_to_tuple(span: &Option<GraphSpan>) -> Option<(DateTime<Utc>, Durat
             return None;
         }
     };
-    let end = if span.end == "now" {
-        Utc::now()
-    } else if let Ok(end) = DateTime::parse_from_rfc3339(&span.end) {
-        end.to_utc()
-    } else {
+    let end = parse_relative_time(&span.end).unwrap_or_else(|| {
         error!(?span.end, "Invalid DateTime using current time.");
         Utc::now()
-    };
+    });
     Some((end, duration, step_duration))
 }
 
+fn span_warnings(
+    span: &Option<GraphSpan>,
+    retentions: Vec<&Option<String>>,
+    check_points: bool,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let (end, duration, step_duration) = match graph_span_to_tuple(span) {
+        Some(tuple) => tuple,
+        None => return warnings,
+    };
+    if check_points && step_duration.num_seconds() > 0 {
+        let points = duration.num_seconds() / step_duration.num_seconds();
+        if points > MAX_POINTS_PER_SERIES {
+            warnings.push(format!(
+                "span of {} at a step of {} is {} points which exceeds the limit of {}",
+                duration, step_duration, points, MAX_POINTS_PER_SERIES
+            ));
+        }
+    }
+    for retention in retentions.into_iter().flatten() {
+        if let Some(retention_duration) = duration_from_string(retention) {
+            if end - duration < Utc::now() - retention_duration {
+                warnings.push(format!(
+                    "span starting at {} is older than the source retention of {}",
+                    end - duration,
+                    retention
+                ));
+            }
+        } else {
+            warnings.push(format!("invalid retention hint {}", retention));
+        }
+    }
+    warnings
+}
+
+fn check_span(span: &Option<GraphSpan>) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Some(span) = span {
+        match span.start {
+            Some(ref start) if parse_relative_time(start).is_none() => {
+                problems.push(format!("invalid start {}", start));
+            }
+            Some(ref start) => {
+                if span.span_duration().is_none() && parse_relative_time(&span.end).is_some() {
+                    problems.push(format!("start {} is not before end {}", start, span.end));
+                }
+            }
+            None if span.duration.is_empty() => {
+                problems.push("span needs either a start or a duration".to_string());
+            }
+            None => {
+                if duration_from_string(&span.duration).is_none() {
+                    problems.push(format!("invalid duration {}", span.duration));
+                }
+            }
+        }
+        if duration_from_string(&span.step_duration).is_none() {
+            problems.push(format!("invalid step_duration {}", span.step_duration));
+        }
+        if parse_relative_time(&span.end).is_none() {
+            problems.push(format!("invalid end {}", span.end));
+        }
+    }
+    problems
+}
+
+fn check_timeout(timeout: &Option<String>) -> Option<String> {
+    let timeout = timeout.as_ref()?;
+    match duration_from_string(timeout) {
+        Some(_) => None,
+        None => Some(format!("invalid timeout {}", timeout)),
+    }
+}
+
+fn check_source(source: &str) -> Option<String> {
+    match reqwest::Url::parse(source) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => None,
+        Ok(url) => Some(format!("source {} has unsupported scheme {}", source, url.scheme())),
+        Err(e) => Some(format!("invalid source {}: {}", source, e)),
+    }
+}
+
+/// A syntax sanity check for queries. Catches empty queries, unbalanced brackets, and
+/// unterminated strings. It isn't a full PromQL parser so the source can still reject a query
+/// that passes.
+fn check_query_syntax(query: &str) -> Option<String> {
+    if query.trim().is_empty() {
+        return Some("empty query".to_string());
+    }
+    let mut open = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    for c in query.chars() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' && q != '`' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' | '`' => quote = Some(c),
+            '(' | '[' | '{' => open.push(c),
+            ')' | ']' | '}' => {
+                let expected = match c {
+                    ')' => '(',
+                    ']' => '[',
+                    _ => '{',
+                };
+                if open.pop() != Some(expected) {
+                    return Some(format!("unbalanced {} in query {}", c, query));
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(q) = quote {
+        return Some(format!("unterminated {} string in query {}", q, query));
+    }
+    if let Some(c) = open.pop() {
+        return Some(format!("unclosed {} in query {}", c, query));
+    }
+    None
+}
+
+/// Checks the structure of a dashboard without touching the network. Spans have to parse,
+/// sources have to be http or https urls, and queries have to pass `check_query_syntax`. Every
+/// problem is reported in the error rather than just the first.
+pub fn offline_validate(dash: &Dashboard) -> Result<()> {
+    let mut problems = check_span(&dash.span);
+    problems.extend(check_timeout(&dash.timeout));
+    for (idx, graph) in dash.graphs.iter().flatten().enumerate() {
+        let mut graph_problems = check_span(&graph.span);
+        graph_problems.extend(check_timeout(&graph.timeout));
+        for plot in graph.plots.iter() {
+            graph_problems.extend(check_source(&plot.source));
+            graph_problems.extend(check_timeout(&plot.timeout));
+            for source in plot.sources.values() {
+                graph_problems.extend(check_source(source));
+            }
+            graph_problems.extend(check_query_syntax(&plot.query));
+        }
+        problems.extend(
+            graph_problems
+                .into_iter()
+                .map(|p| format!("graph {} ({}): {}", idx, graph.title, p)),
+        );
+    }
+    for (idx, log) in dash.logs.iter().flatten().enumerate() {
+        let mut log_problems = check_span(&log.span);
+        log_problems.extend(check_timeout(&log.timeout));
+        log_problems.extend(check_source(&log.source));
+        log_problems.extend(check_query_syntax(&log.query));
+        problems.extend(
+            log_problems
+                .into_iter()
+                .map(|p| format!("log {} ({}): {}", idx, log.title, p)),
+        );
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(problems.join("\n")))
+    }
+}
+
+/// Whether a custom_css value is a url to link directly instead of a file to serve.
+fn is_css_url(css: &str) -> bool {
+    css.starts_with("http://") || css.starts_with("https://") || css.starts_with('/')
+}
+
+impl Dashboard {
+    /// Every prometheus source the dashboard's graphs can query without a trailing slash.
+    /// Includes the plot `sources` alternatives and slo sources.
+    pub fn prom_sources(&self) -> Vec<&str> {
+        let mut sources = Vec::new();
+        for graph in self.graphs.iter().flatten() {
+            for plot in graph.plots.iter() {
+                sources.push(plot.source.as_str());
+                sources.extend(plot.sources.values().map(String::as_str));
+            }
+            if let Some(ref slo) = graph.slo {
+                sources.push(slo.source.as_str());
+            }
+        }
+        let mut sources = sources
+            .into_iter()
+            .map(|source| source.trim_end_matches('/'))
+            .collect::<Vec<_>>();
+        sources.sort();
+        sources.dedup();
+        sources
+    }
+
+    /// Where the dashboard's custom stylesheet is served from if it has one.
+    pub fn custom_css_href(&self, dash_idx: usize) -> Option<String> {
+        let css = self.custom_css.as_ref()?;
+        if is_css_url(css) {
+            Some(css.clone())
+        } else {
+            Some(format!("/static/dash/{}/custom.css", dash_idx))
+        }
+    }
+
+    /// Where the dashboard's custom_js module is served from if it has one.
+    pub fn custom_js_src(&self, dash_idx: usize) -> Option<String> {
+        self.custom_js
+            .as_ref()
+            .map(|_| format!("/static/dash/{}/custom.js", dash_idx))
+    }
+
+    /// Assigns a cache ttl if the dashboard doesn't specify its own.
+    pub fn set_default_cache_seconds(&mut self, seconds: u64) {
+        self.cache_seconds.get_or_insert(seconds);
+    }
+
+    /// Assigns a query budget if the dashboard doesn't specify its own.
+    pub fn set_default_query_budget(&mut self, limit: u32) {
+        self.query_budget.get_or_insert(limit);
+    }
+
+    /// The query budget and its limit. None when the dashboard has no limit.
+    pub fn query_budget(&self) -> Option<(&QueryBudget, u32)> {
+        self.query_budget
+            .filter(|limit| *limit > 0)
+            .map(|limit| (&self.budget, limit))
+    }
+
+    /// Assigns a limit to every log stream that doesn't specify its own. A stream's own `limit`
+    /// always takes precedence over this default.
+    pub fn set_default_log_limit(&mut self, limit: usize) {
+        if let Some(ref mut logs) = self.logs {
+            for log in logs.iter_mut() {
+                if log.limit.is_none() {
+                    log.limit = Some(limit);
+                }
+            }
+        }
+    }
+
+    /// Assigns a max label length to every graph and log stream that doesn't specify its own.
+    pub fn set_default_max_label_len(&mut self, max: usize) {
+        if let Some(ref mut graphs) = self.graphs {
+            for graph in graphs.iter_mut() {
+                graph.max_label_len.get_or_insert(max);
+            }
+        }
+        if let Some(ref mut logs) = self.logs {
+            for log in logs.iter_mut() {
+                log.max_label_len.get_or_insert(max);
+            }
+        }
+    }
+
+    /// Makes every log stream fail on malformed results instead of skipping them.
+    pub fn set_strict_parse(&mut self) {
+        if let Some(ref mut logs) = self.logs {
+            for log in logs.iter_mut() {
+                log.strict_parse = true;
+            }
+        }
+    }
+
+    /// Adds labels to redact from every graph and log stream in addition to their own.
+    pub fn add_redacted_labels(&mut self, labels: &[String]) {
+        if let Some(ref mut graphs) = self.graphs {
+            for graph in graphs.iter_mut() {
+                graph.redact_labels.extend(labels.iter().cloned());
+            }
+        }
+        if let Some(ref mut logs) = self.logs {
+            for log in logs.iter_mut() {
+                log.redact_labels.extend(labels.iter().cloned());
+            }
+        }
+    }
+
+    pub fn get_view(&self, name: &str) -> Option<&FilterView> {
+        self.views.as_ref()?.iter().find(|v| v.name == name)
+    }
+
+    /// Checks the effective span of every graph and log against the limits of their sources.
+    /// Returns a list of warnings for queries that will likely be rejected or render empty.
+    pub fn span_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if let Some(ref graphs) = self.graphs {
+            for (idx, graph) in graphs.iter().enumerate() {
+                let span = if graph.span.is_some() { &graph.span } else { &self.span };
+                let retentions = graph.plots.iter().map(|p| &p.retention).collect();
+                for warning in span_warnings(span, retentions, true) {
+                    warnings.push(format!("graph {} ({}): {}", idx, graph.title, warning));
+                }
+            }
+        }
+        if let Some(ref logs) = self.logs {
+            for (idx, log) in logs.iter().enumerate() {
+                let span = if log.span.is_some() { &log.span } else { &self.span };
+                for warning in span_warnings(span, vec![&log.retention], false) {
+                    warnings.push(format!("log {} ({}): {}", idx, log.title, warning));
+                }
+            }
+        }
+        warnings
+    }
+}
+
+/// d3 tick formats for graphs that have a unit but no d3_tick_format. Byte counts and seconds
+/// use SI prefixes. e.g. 1.5G or 250m.
+pub const DEFAULT_UNIT_TICK_FORMATS: [(&str, &str); 3] =
+    [("bytes", "~s"), ("percent", ".0%"), ("seconds", "~s")];
+
 impl Graph {
+    /// The graph's d3_tick_format or the format for its unit from the dashboard or the defaults.
+    pub fn tick_format(&self, dash: &Dashboard) -> Option<String> {
+        if let Some(ref format) = self.d3_tick_format {
+            return Some(format.clone());
+        }
+        let unit = self.unit.as_ref()?;
+        dash.unit_tick_formats.get(unit).cloned().or_else(|| {
+            DEFAULT_UNIT_TICK_FORMATS
+                .iter()
+                .find(|(u, _)| u == unit)
+                .map(|(_, format)| format.to_string())
+        })
+    }
+
+    /// Whether the graph's results are sparse snapshots that should be drawn as markers.
+    pub fn is_snapshot(&self) -> bool {
+        matches!(self.query_type, QueryType::Scalar) && self.snapshots.is_some()
+    }
+
+    /// The overview and detail spans when the queried span is longer than the detail window.
+    pub fn overview_detail_spans(
+        &self,
+        dash: &Dashboard,
+        query_span: &Option<GraphSpan>,
+    ) -> Option<(GraphSpan, GraphSpan)> {
+        let config = self.overview_detail.as_ref()?;
+        if !matches!(self.query_type, QueryType::Range) {
+            return None;
+        }
+        // Same precedence as the query connections use.
+        let span = [query_span, &self.span, &dash.span]
+            .into_iter()
+            .find(|span| graph_span_to_tuple(span).is_some())?
+            .as_ref()?;
+        let duration = span.span_duration()?;
+        if duration <= duration_from_string(&config.detail_duration)? {
+            return None;
+        }
+        Some((
+            GraphSpan {
+                end: span.end.clone(),
+                start: span.start.clone(),
+                duration: span.duration.clone(),
+                step_duration: config.overview_step.clone(),
+            },
+            GraphSpan {
+                end: span.end.clone(),
+                start: None,
+                duration: config.detail_duration.clone(),
+                step_duration: config.detail_step.clone(),
+            },
+        ))
+    }
+
+    /// Returns the graph's annotations and the dashboard's static annotations that fall inside
+    /// the span this graph will be queried for.
+    pub fn annotations_in_span(
+        &self,
+        dash: &Dashboard,
+        query_span: &Option<GraphSpan>,
+    ) -> Vec<Annotation> {
+        // Same precedence as the query connections use.
+        let (end, duration) = match graph_span_to_tuple(query_span)
+            .or_else(|| graph_span_to_tuple(&self.span))
+            .or_else(|| graph_span_to_tuple(&dash.span))
+        {
+            Some((end, duration, _)) => (end, duration),
+            None => (Utc::now(), Duration::minutes(10)),
+        };
+        let start = end - duration;
+        self.annotations
+            .iter()
+            .cloned()
+            .chain(dash.annotations.iter().filter_map(|a| a.to_annotation()))
+            .filter(|a| a.timestamp >= start && a.timestamp <= end)
+            .collect()
+    }
+
     pub fn get_query_connections<'conn, 'graph: 'conn>(
         &'graph self,
         graph_span: &'graph Option<GraphSpan>,
         query_span: &'graph Option<GraphSpan>,
         filters: &'graph Option<HashMap<&'graph str, &'graph str>>,
+        source: Option<&'graph str>,
+        origin: &'graph str,
     ) -> Vec<PromQueryConn<'conn>> {
         let mut conns = Vec::new();
         for plot in self.plots.iter() {
+            // Plots without the named source keep their default source.
+            let plot_source = source
+                .and_then(|name| plot.sources.get(name))
+                .unwrap_or(&plot.source);
             debug!(
                 query = plot.query,
-                source = plot.source,
+                source = plot_source,
                 filters = ?filters,
                 "Getting query connection for graph",
             );
+            let mut meta = plot.config.clone();
+            meta.clamp_negative = plot.clamp_negative.unwrap_or(self.clamp_negative);
             let mut conn = PromQueryConn::new(
-                &plot.source,
+                plot_source,
                 &plot.query,
-                self.query_type.clone(),
-                plot.config.clone(),
-            );
+                plot.query_type.clone().unwrap_or(self.query_type.clone()),
+                meta,
+            )
+            .with_source_type(plot.source_type.clone(), plot.dedup, plot.partial_response)
+            .with_auth(plot.auth.as_ref())
+            .with_org_id(plot.org_id.as_deref())
+            .with_origin(origin);
             if let Some(filters) = filters {
                 debug!(?filters, "query connection with filters");
                 conn = conn.with_filters(filters);
             }
             // Query params take precendence over all other settings. Then graph settings take
             // precedences and finally the dashboard settings take precendence
-            if let Some((end, duration, step_duration)) = graph_span_to_tuple(query_span) {
-                conn = conn.with_span(end, duration, step_duration);
-            } else if let Some((end, duration, step_duration)) = graph_span_to_tuple(&self.span) {
-                conn = conn.with_span(end, duration, step_duration);
-            } else if let Some((end, duration, step_duration)) = graph_span_to_tuple(graph_span) {
-                conn = conn.with_span(end, duration, step_duration);
+            let span = [query_span, &self.span, graph_span]
+                .into_iter()
+                .find_map(|span| Some((span.as_ref()?, graph_span_to_tuple(span)?)));
+            if let Some((span, (end, duration, step_duration))) = span {
+                conn = conn
+                    .with_span(end, duration, step_duration)
+                    .with_live_end(span.end.trim().starts_with("now"));
             }
             conns.push(conn);
         }
         conns
     }
+
+    /// True if every query of the graph can have scope matchers added. That requires a
+    /// FILTERS placeholder in every selector of every plot query so `a{FILTERS} / b` isn't
+    /// scopable. Slo queries have no placeholder.
+    pub fn scopable(&self) -> bool {
+        self.slo.is_none()
+            && self
+                .plots
+                .iter()
+                .all(|plot| crate::query::every_selector_filtered(&plot.query))
+    }
+
+    /// Checks that a `source` query param names a source every plot with named sources has.
+    pub fn check_source_name(&self, name: &str) -> Result<()> {
+        let named = self.plots.iter().filter(|plot| !plot.sources.is_empty());
+        let mut any = false;
+        for plot in named {
+            any = true;
+            if !plot.sources.contains_key(name) {
+                return Err(anyhow::anyhow!(
+                    "No source named {} for query {}. Expected one of: {}",
+                    name,
+                    plot.query,
+                    plot.sources.keys().cloned().collect::<Vec<_>>().join(", ")
+                ));
+            }
+        }
+        if !any {
+            return Err(anyhow::anyhow!("Graph {} has no named sources", self.title));
+        }
+        Ok(())
+    }
 }
 
 impl LogStream {
@@ -264,13 +1545,18 @@ impl LogStream {
         &'stream self,
         graph_span: &'stream Option<GraphSpan>,
         query_span: &'stream Option<GraphSpan>,
+        origin: &'stream str,
     ) -> LokiConn<'conn> {
         debug!(
             query = self.query,
             source = self.source,
             "Getting query connection for log streams",
         );
-        let mut conn = LokiConn::new(&self.source, &self.query, self.query_type.clone());
+        let mut conn =
+            LokiConn::new(&self.source, &self.query, self.query_type.clone())
+                .with_auth(self.auth.as_ref())
+                .with_org_id(self.org_id.as_deref())
+                .with_origin(origin);
         // Query params take precendence over all other settings. Then graph settings take
         // precedences and finally the dashboard settings take precendence
         if let Some((end, duration, step_duration)) = graph_span_to_tuple(query_span) {
@@ -283,11 +1569,773 @@ impl LogStream {
         if let Some(limit) = self.limit {
             conn = conn.with_limit(limit);
         }
+        if let Some(ref api_prefix) = self.api_prefix {
+            conn = conn.with_api_prefix(api_prefix);
+        }
         conn
     }
 }
 
-pub fn read_dashboard_list(path: &Path) -> anyhow::Result<Vec<Dashboard>> {
-    let f = std::fs::File::open(path)?;
-    Ok(serde_yaml::from_reader(f)?)
+/// Returns the indexes and titles of the dashboards to list in the menu in display order.
+/// Dashboards with a `menu_order` come first sorted by order then title. The rest follow in
+/// config order.
+pub fn menu_titles(dashboards: &[Dashboard]) -> Vec<(usize, String)> {
+    let mut visible = dashboards
+        .iter()
+        .enumerate()
+        .filter(|(_, d)| !d.hidden)
+        .collect::<Vec<(usize, &Dashboard)>>();
+    visible.sort_by(|(_, left), (_, right)| match (left.menu_order, right.menu_order) {
+        (Some(l), Some(r)) => l.cmp(&r).then_with(|| left.title.cmp(&right.title)),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+    visible
+        .into_iter()
+        .map(|(idx, d)| (idx, d.title.clone()))
+        .collect()
+}
+
+fn read_annotations(path: &Path) -> anyhow::Result<Vec<Annotation>> {
+    let f = std::fs::File::open(path)
+        .with_context(|| format!("Unable to open annotations file {}", path.display()))?;
+    serde_yaml::from_reader(f)
+        .with_context(|| format!("Invalid annotations file {}", path.display()))
+}
+
+fn load_custom_css(dashboards: &mut [Dashboard], config_path: &Path) -> anyhow::Result<()> {
+    let base = config_path.parent().unwrap_or(Path::new("."));
+    for dash in dashboards.iter_mut() {
+        if let Some(css) = dash.custom_css.as_ref().filter(|css| !is_css_url(css)) {
+            let path = base.join(css);
+            dash.custom_css_content = Some(
+                std::fs::read_to_string(&path)
+                    .with_context(|| format!("Unable to read custom css {}", path.display()))?,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Loads every dashboard's custom_js from the assets dir. Paths that resolve outside of the
+/// assets dir are an error so a config can't be used to serve arbitrary files.
+fn load_custom_js(dashboards: &mut [Dashboard], assets_dir: Option<&Path>) -> anyhow::Result<()> {
+    for dash in dashboards.iter_mut() {
+        let Some(js) = dash.custom_js.as_ref() else {
+            continue;
+        };
+        let assets_dir = assets_dir.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Dashboard {} has custom_js but there is no --assets-dir",
+                dash.title
+            )
+        })?;
+        let assets_dir = assets_dir
+            .canonicalize()
+            .with_context(|| format!("Invalid assets dir {}", assets_dir.display()))?;
+        let path = assets_dir
+            .join(js)
+            .canonicalize()
+            .with_context(|| format!("Unable to find custom js {}", js.display()))?;
+        if !path.starts_with(&assets_dir) {
+            return Err(anyhow::anyhow!(
+                "Custom js {} for dashboard {} is outside of the assets dir",
+                js.display(),
+                dash.title
+            ));
+        }
+        dash.custom_js_content = Some(
+            std::fs::read_to_string(&path)
+                .with_context(|| format!("Unable to read custom js {}", path.display()))?,
+        );
+    }
+    Ok(())
+}
+
+fn load_annotations(dashboards: &mut [Dashboard], config_path: &Path) -> anyhow::Result<()> {
+    let base = config_path.parent().unwrap_or(Path::new("."));
+    for dash in dashboards.iter_mut() {
+        if let Some(ref mut graphs) = dash.graphs {
+            for graph in graphs.iter_mut() {
+                if let Some(ref file) = graph.annotations_file {
+                    graph.annotations = read_annotations(&base.join(file))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The config file format version this build reads.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// A config file with an explicit format version.
+#[derive(Deserialize)]
+struct VersionedConfig {
+    #[allow(dead_code)]
+    version: u32,
+    dashboards: Vec<Dashboard>,
+    #[serde(default)]
+    sources: HashMap<String, SourceDefinition>,
+}
+
+/// A source defined once in the config's `sources` map. Plots, logs and slos reference it with
+/// `source: "@name"`. Their own auth, org_id and timeout settings override the source's.
+#[derive(Deserialize, Clone, Debug)]
+pub struct SourceDefinition {
+    pub url: String,
+    pub auth: Option<SourceAuth>,
+    pub org_id: Option<String>,
+    pub timeout: Option<String>,
+}
+
+/// Named sources from every config file. A name can only be defined once.
+type SourceMap = HashMap<String, SourceDefinition>;
+
+/// The definition a `@name` source refers to. None for a literal url.
+fn source_reference<'s>(
+    source: &str,
+    sources: &'s SourceMap,
+    dash: &str,
+) -> anyhow::Result<Option<&'s SourceDefinition>> {
+    let Some(name) = source.strip_prefix('@') else {
+        return Ok(None);
+    };
+    sources
+        .get(name)
+        .map(Some)
+        .ok_or_else(|| anyhow::anyhow!("Unknown source @{} in dashboard {}", name, dash))
+}
+
+/// Replaces a `@name` source with its url and fills in any connection settings that weren't
+/// set where it was referenced.
+fn resolve_source(
+    def: &SourceDefinition,
+    source: &mut String,
+    auth: &mut Option<SourceAuth>,
+    org_id: &mut Option<String>,
+    timeout: Option<&mut Option<String>>,
+) {
+    source.clone_from(&def.url);
+    if auth.is_none() {
+        auth.clone_from(&def.auth);
+    }
+    if org_id.is_none() {
+        org_id.clone_from(&def.org_id);
+    }
+    if let Some(timeout) = timeout.filter(|timeout| timeout.is_none()) {
+        timeout.clone_from(&def.timeout);
+    }
+}
+
+/// Resolves every `@name` source in the dashboards. Named alternatives in a plot's `sources`
+/// only take the url since the plot's auth applies to all of them. Unknown names are an error.
+fn resolve_sources(dashboards: &mut [Dashboard], sources: &SourceMap) -> anyhow::Result<()> {
+    for dash in dashboards.iter_mut() {
+        let title = dash.title.as_str();
+        for graph in dash.graphs.iter_mut().flatten() {
+            for plot in graph.plots.iter_mut() {
+                if let Some(def) = source_reference(&plot.source, sources, title)? {
+                    resolve_source(
+                        def,
+                        &mut plot.source,
+                        &mut plot.auth,
+                        &mut plot.org_id,
+                        Some(&mut plot.timeout),
+                    );
+                }
+                for url in plot.sources.values_mut() {
+                    if let Some(def) = source_reference(url, sources, title)? {
+                        url.clone_from(&def.url);
+                    }
+                }
+            }
+            if let Some(slo) = graph.slo.as_mut() {
+                if let Some(def) = source_reference(&slo.source, sources, title)? {
+                    resolve_source(def, &mut slo.source, &mut slo.auth, &mut slo.org_id, None);
+                }
+            }
+        }
+        for log in dash.logs.iter_mut().flatten() {
+            if let Some(def) = source_reference(&log.source, sources, title)? {
+                resolve_source(
+                    def,
+                    &mut log.source,
+                    &mut log.auth,
+                    &mut log.org_id,
+                    Some(&mut log.timeout),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_config_version(version: u32) -> anyhow::Result<()> {
+    if version == CONFIG_VERSION {
+        return Ok(());
+    }
+    if version > CONFIG_VERSION {
+        return Err(anyhow::anyhow!(
+            "Config version {} is newer than the supported version {}. Upgrade heracles to load this config.",
+            version,
+            CONFIG_VERSION
+        ));
+    }
+    Err(anyhow::anyhow!(
+        "Config version {} is no longer supported. Migrate the config to version {}.",
+        version,
+        CONFIG_VERSION
+    ))
+}
+
+/// Default time each playlist dashboard is shown for.
+pub const DEFAULT_PLAYLIST_DWELL: &str = "60s";
+
+fn default_playlist_dwell() -> String {
+    DEFAULT_PLAYLIST_DWELL.to_string()
+}
+
+fn default_playlist_loop() -> bool {
+    true
+}
+
+/// A dashboard in a playlist and how long it is shown for.
+#[derive(Deserialize, Clone, Debug)]
+pub struct PlaylistItem {
+    /// Index of the dashboard in the config.
+    pub dashboard: usize,
+    /// A duration like the span durations. e.g. `30s` or `2 minutes`. Defaults to 60s.
+    #[serde(default = "default_playlist_dwell")]
+    pub dwell: String,
+    /// Saved view to show the dashboard with.
+    pub view: Option<String>,
+}
+
+/// Dashboards a wall display rotates through at `/playlist/<name>`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Playlist {
+    pub name: String,
+    pub items: Vec<PlaylistItem>,
+    /// Start over after the last item. Defaults to true. Otherwise the last dashboard stays up.
+    #[serde(default = "default_playlist_loop", rename = "loop")]
+    pub looping: bool,
+}
+
+impl PlaylistItem {
+    /// The dwell time in seconds. Only valid after `read_playlists` has checked it.
+    pub fn dwell_seconds(&self) -> i64 {
+        duration_from_string(&self.dwell)
+            .map(|d| d.num_seconds())
+            .unwrap_or(60)
+    }
+}
+
+#[derive(Deserialize)]
+struct PlaylistConfig {
+    #[serde(default)]
+    playlists: Vec<Playlist>,
+}
+
+/// Reads the `playlists` from versioned config mappings. Plain dashboard lists have no
+/// playlists. With a config directory every file's playlists are read and the dashboard
+/// indexes are into the combined list. Every item has to name a loaded dashboard and have a
+/// valid dwell time.
+pub fn read_playlists(path: &Path, dashboards: &[Dashboard]) -> anyhow::Result<Vec<Playlist>> {
+    let mut playlists = Vec::new();
+    for file in config_files(path)? {
+        let contents = read_config_file(&file)?;
+        if !matches!(
+            serde_yaml::from_str::<serde_yaml::Value>(&contents)?,
+            serde_yaml::Value::Mapping(_)
+        ) {
+            continue;
+        }
+        let config: PlaylistConfig = serde_yaml::from_str(&contents)?;
+        playlists.extend(config.playlists);
+    }
+    for playlist in playlists.iter() {
+        for item in playlist.items.iter() {
+            if item.dashboard >= dashboards.len() {
+                return Err(anyhow::anyhow!(
+                    "Playlist {} has no such dashboard {}",
+                    playlist.name,
+                    item.dashboard
+                ));
+            }
+            match duration_from_string(&item.dwell) {
+                Some(dwell) if dwell.num_seconds() > 0 => {}
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "Playlist {} has an invalid dwell {}",
+                        playlist.name,
+                        item.dwell
+                    ))
+                }
+            }
+        }
+    }
+    Ok(playlists)
+}
+
+/// Parses the config file contents. The file is either a list of dashboards, which is read as
+/// the current version, or a mapping with a `version` and a `dashboards` list. Only the mapping
+/// can define named `sources`.
+fn parse_dashboard_list(contents: &str) -> anyhow::Result<(Vec<Dashboard>, SourceMap)> {
+    let version = match serde_yaml::from_str::<serde_yaml::Value>(contents)? {
+        serde_yaml::Value::Mapping(m) => m.get("version").cloned(),
+        _ => return Ok((serde_yaml::from_str(contents)?, SourceMap::new())),
+    };
+    match version {
+        Some(serde_yaml::Value::Number(n)) => {
+            let version = n
+                .as_u64()
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| anyhow::anyhow!("Invalid config version {}", n))?;
+            check_config_version(version)?;
+        }
+        Some(v) => return Err(anyhow::anyhow!("Invalid config version {:?}", v)),
+        None => return Err(anyhow::anyhow!("Config mapping is missing a version field")),
+    }
+    // Parse again from the text so errors keep their line and column.
+    let config: VersionedConfig = serde_yaml::from_str(contents)?;
+    Ok((config.dashboards, config.sources))
+}
+
+/// Substitutes `${VAR}` and `${VAR:-default}` in the config text with the process environment.
+/// The default is used when the variable is unset or empty. Without a default an empty variable
+/// substitutes as empty. Anything that isn't a valid variable name, like the
+/// `${labels.instance}` in a name_format template, is left alone. `$${` is a literal `${`. An
+/// unset variable without a default is an error naming the variable and the file.
+fn interpolate_env(contents: &str, file: &Path) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(contents.len());
+    let mut rest = contents;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        let body = &rest[start + 2..];
+        let line = contents[..contents.len() - body.len()].matches('\n').count() + 1;
+        let end = body.find('}').ok_or_else(|| {
+            anyhow::anyhow!("Unterminated ${{ in {} line {}", file.display(), line)
+        })?;
+        let (name, default) = match body[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&body[..end], None),
+        };
+        let valid_name = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            // Not a variable. e.g. a name_format template like `${labels.instance}`.
+            out.push_str("${");
+            rest = body;
+            continue;
+        }
+        match (std::env::var(name).ok(), default) {
+            (Some(value), None) => out.push_str(&value),
+            (Some(value), Some(_)) if !value.is_empty() => out.push_str(&value),
+            (_, Some(default)) => out.push_str(default),
+            (None, None) => {
+                return Err(anyhow::anyhow!(
+                    "Environment variable {} is not set and has no default in {} line {}",
+                    name,
+                    file.display(),
+                    line
+                ))
+            }
+        }
+        rest = &body[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Reads a config file with environment variables interpolated.
+fn read_config_file(file: &Path) -> anyhow::Result<String> {
+    let contents = std::fs::read_to_string(file)
+        .with_context(|| format!("Unable to read config file {}", file.display()))?;
+    interpolate_env(&contents, file)
+}
+
+/// The config files at the path. A directory's `*.yaml` and `*.yml` files are returned sorted
+/// by name so the dashboard order is deterministic. Any other path is returned as is.
+fn config_files(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path)
+        .with_context(|| format!("Unable to read config directory {}", path.display()))?
+    {
+        let file = entry?.path();
+        let is_yaml = matches!(
+            file.extension().and_then(|ext| ext.to_str()),
+            Some("yaml") | Some("yml")
+        );
+        if is_yaml && file.is_file() {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Errors if two of the config files have dashboards with the same title.
+fn check_duplicate_titles(files: &[(PathBuf, Vec<Dashboard>)]) -> anyhow::Result<()> {
+    let mut seen: HashMap<&str, &Path> = HashMap::new();
+    let mut duplicates = Vec::new();
+    for (file, dashboards) in files {
+        for dash in dashboards {
+            match seen.get(dash.title.as_str()) {
+                Some(first) if first != file => duplicates.push(format!(
+                    "{} is in both {} and {}",
+                    dash.title,
+                    first.display(),
+                    file.display()
+                )),
+                Some(_) => {}
+                None => {
+                    seen.insert(&dash.title, file);
+                }
+            }
+        }
+    }
+    if duplicates.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Duplicate dashboard titles:\n{}",
+            duplicates.join("\n")
+        ))
+    }
+}
+
+/// Reads the dashboards from the config file or from every yaml file in the config directory.
+/// If `max_dashboards` is set any dashboards past the limit are dropped with a warning before
+/// their annotations get loaded. Annotation and custom css files are relative to the file that
+/// references them. Custom js is loaded from `assets_dir`. Environment variables are
+/// interpolated before parsing. See `interpolate_env`. `@name` sources are resolved against
+/// the `sources` of every file.
+pub fn read_dashboard_list(
+    path: &Path,
+    max_dashboards: Option<usize>,
+    assets_dir: Option<&Path>,
+) -> anyhow::Result<Vec<Dashboard>> {
+    let mut files = Vec::new();
+    let mut sources = SourceMap::new();
+    let mut remaining = max_dashboards.unwrap_or(usize::MAX);
+    for file in config_files(path)? {
+        let contents = read_config_file(&file)?;
+        let (mut dashboards, file_sources) = parse_dashboard_list(&contents)
+            .with_context(|| format!("Invalid config file {}", file.display()))?;
+        for (name, def) in file_sources {
+            if sources.insert(name.clone(), def).is_some() {
+                return Err(anyhow::anyhow!(
+                    "Source {} is defined more than once. The second is in {}",
+                    name,
+                    file.display()
+                ));
+            }
+        }
+        for dash in dashboards.iter() {
+            for annotation in dash.annotations.iter() {
+                if parse_relative_time(&annotation.timestamp).is_none() {
+                    return Err(anyhow::anyhow!(
+                        "Invalid annotation timestamp {} in dashboard {}",
+                        annotation.timestamp,
+                        dash.title
+                    ));
+                }
+            }
+        }
+        if dashboards.len() > remaining {
+            for dash in dashboards.drain(remaining..) {
+                warn!(
+                    file = ?file,
+                    dashboard = dash.title,
+                    max_dashboards,
+                    "Skipping dashboard past the dashboard limit"
+                );
+            }
+        }
+        remaining -= dashboards.len();
+        files.push((file, dashboards));
+    }
+    if files.len() > 1 {
+        check_duplicate_titles(&files)?;
+    }
+    // Sources can be referenced from any file so they are resolved once all are read.
+    for (_, dashboards) in files.iter_mut() {
+        resolve_sources(dashboards, &sources)?;
+    }
+    let mut all = Vec::new();
+    for (file, mut dashboards) in files {
+        load_annotations(&mut dashboards, &file)?;
+        load_custom_css(&mut dashboards, &file)?;
+        all.extend(dashboards);
+    }
+    load_custom_js(&mut all, assets_dir)?;
+    Ok(all)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use axum::{extract::ConnectInfo, http::header, routing::get, Router};
+
+    use super::*;
+    use crate::query::test_server::{points, serve, PROM_MATRIX};
+
+    fn threshold(op: ComparisonOp, value: f64, color: &str) -> Threshold {
+        Threshold {
+            op,
+            value,
+            color: color.to_string(),
+        }
+    }
+
+    #[test]
+    fn threshold_operators() {
+        let cases = [
+            (ComparisonOp::Gt, [None, None, Some("red")]),
+            (ComparisonOp::Gte, [None, Some("red"), Some("red")]),
+            (ComparisonOp::Lt, [Some("red"), None, None]),
+            (ComparisonOp::Lte, [Some("red"), Some("red"), None]),
+        ];
+        for (op, expected) in cases {
+            let thresholds = [threshold(op, 100.0, "red")];
+            let colors = [99.0, 100.0, 101.0].map(|value| threshold_color(&thresholds, value));
+            assert_eq!(colors, expected);
+            assert_eq!(threshold_color(&thresholds, f64::NAN), None);
+        }
+    }
+
+    #[test]
+    fn first_matching_threshold_wins() {
+        let thresholds = [
+            threshold(ComparisonOp::Gt, 100.0, "red"),
+            threshold(ComparisonOp::Gt, 50.0, "yellow"),
+        ];
+        assert_eq!(threshold_color(&thresholds, 150.0), Some("red"));
+        assert_eq!(threshold_color(&thresholds, 75.0), Some("yellow"));
+        assert_eq!(threshold_color(&thresholds, 10.0), None);
+        assert_eq!(threshold_color(&[], 10.0), None);
+    }
+
+    #[test]
+    fn value_filter_operators_and_reducers() {
+        // last 20, max 90, mean 40.
+        let series = points(30, &[10.0, 90.0, 20.0]);
+        let cases = [
+            (ComparisonOp::Gt, [false, true, false]),
+            (ComparisonOp::Gte, [false, true, true]),
+            (ComparisonOp::Lt, [true, false, false]),
+            (ComparisonOp::Lte, [true, false, true]),
+        ];
+        let reducers = [SeriesReducer::Last, SeriesReducer::Max, SeriesReducer::Mean];
+        for (op, expected) in cases {
+            let matches = reducers.map(|by| ValueFilter { op, value: 40.0, by }.matches(&series));
+            assert_eq!(matches, expected);
+        }
+    }
+
+    #[test]
+    fn value_filter_drops_empty_series_and_counts_the_dropped() {
+        let filter: ValueFilter = serde_yaml::from_str("{op: gt, value: 80}").unwrap();
+        assert!(matches!(filter.by, SeriesReducer::Last));
+        assert!(!filter.matches(&[]));
+        let mut plot = MetricsQueryResult::Series(vec![
+            (HashMap::new(), PlotConfig::default(), points(30, &[95.0])),
+            (HashMap::new(), PlotConfig::default(), points(30, &[50.0])),
+            (HashMap::new(), PlotConfig::default(), points(30, &[])),
+        ]);
+        assert_eq!(plot.retain_series(|points| filter.matches(points)), 2);
+        assert_eq!(plot.series_count(), 1);
+    }
+
+    #[test]
+    fn prune_all_nan_and_constant_series_under_each_flag() {
+        let all_nan = points(30, &[f64::NAN, f64::NAN]);
+        let zero = points(30, &[0.0, 0.0, f64::NAN, 0.0]);
+        let varying = points(30, &[0.0, 1.0, f64::NAN]);
+        // (drop_all_nan, drop_constant) and whether all_nan, zero, and varying are kept.
+        let cases = [
+            ((false, false), [true, true, true]),
+            ((true, false), [false, true, true]),
+            ((false, true), [true, false, true]),
+            ((true, true), [false, false, true]),
+        ];
+        for ((drop_all_nan, drop_constant), expected) in cases {
+            let prune = Prune {
+                drop_all_nan,
+                drop_constant,
+            };
+            let kept = [&all_nan, &zero, &varying].map(|points| prune.keeps(points));
+            assert_eq!(
+                kept, expected,
+                "drop_all_nan {} drop_constant {}",
+                drop_all_nan, drop_constant
+            );
+        }
+    }
+
+    #[test]
+    fn clamp_negative_zeroes_a_negative_spike() {
+        let graph: Graph = serde_yaml::from_str(
+            r#"
+title: Rates
+yaxes: []
+clamp_negative: true
+plots:
+  - source: http://localhost
+    query: rate(requests[5m])
+    config: {}
+  - source: http://localhost
+    query: rate(requests[5m])
+    config: {}
+    clamp_negative: false
+"#,
+        )
+        .unwrap();
+        let data: prometheus_http_query::response::PromqlResult = serde_json::from_str(
+            r#"{"resultType":"matrix","result":[{"metric":{},"values":[[1700000000,"2"],[1700000030,"-40"],[1700000060,"3"]]}]}"#,
+        )
+        .unwrap();
+        let values = graph
+            .get_query_connections(&None, &None, &None, None, "")
+            .into_iter()
+            .map(|conn| match prom_to_samples(data.data().clone(), conn.meta) {
+                MetricsQueryResult::Series(series) => {
+                    series[0].2.iter().map(DataPoint::value).collect::<Vec<_>>()
+                }
+                _ => panic!("Expected a range result"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![vec![2.0, 0.0, 3.0], vec![2.0, -40.0, 3.0]]);
+    }
+
+    #[test]
+    fn graphs_are_only_scopable_with_filters_in_every_selector() {
+        let graph = |query: &str| -> Graph {
+            serde_yaml::from_str(&format!(
+                "{{title: Scoped, yaxes: [], plots: [{{source: http://localhost, query: '{}', config: {{}}}}]}}",
+                query
+            ))
+            .unwrap()
+        };
+        assert!(graph("sum(rate(requests{FILTERS}[5m])) by (job)").scopable());
+        assert!(graph("a{FILTERS} / on(job) b{FILTERS}").scopable());
+        assert!(!graph("a{FILTERS} / b").scopable());
+        assert!(!graph("up{FILTERS} or secret_metric").scopable());
+        assert!(!graph("up").scopable());
+    }
+
+    #[tokio::test]
+    async fn plots_with_the_same_source_share_one_client() {
+        let peers = Arc::new(Mutex::new(Vec::new()));
+        let record = peers.clone();
+        let source = serve(Router::new().route(
+            "/api/v1/query_range",
+            get(move |ConnectInfo(peer): ConnectInfo<SocketAddr>| async move {
+                record.lock().unwrap().push(peer);
+                ([(header::CONTENT_TYPE, "application/json")], PROM_MATRIX)
+            }),
+        ))
+        .await;
+        let graph: Graph = serde_yaml::from_str(&format!(
+            r#"
+title: Shared
+yaxes: []
+plots:
+  - source: {source}
+    query: up
+    config: {{}}
+  - source: {source}
+    query: down
+    config: {{}}
+"#
+        ))
+        .unwrap();
+        for conn in graph.get_query_connections(&None, &None, &None, None, "") {
+            conn.get_results().await.unwrap();
+        }
+        // The second plot's query reuses the first one's pooled connection which it could only
+        // do from the same client.
+        let peers = peers.lock().unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0], peers[1]);
+    }
+
+    /// Writes the files to a fresh directory under the system temp dir.
+    fn config_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("heracles-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, contents) in files {
+            std::fs::write(dir.join(file), contents).unwrap();
+        }
+        dir
+    }
+
+    fn titles(dashboards: &[Dashboard]) -> Vec<&str> {
+        dashboards.iter().map(|dash| dash.title.as_str()).collect()
+    }
+
+    #[test]
+    fn read_dashboard_list_stops_at_max_dashboards() {
+        let dir = config_dir(
+            "max-dashboards",
+            &[
+                ("a.yaml", "- title: a1\n- title: a2\n"),
+                ("b.yaml", "- title: b1\n- title: b2\n"),
+            ],
+        );
+        let read = |max| read_dashboard_list(&dir, max, None).unwrap();
+        assert_eq!(titles(&read(None)), vec!["a1", "a2", "b1", "b2"]);
+        assert_eq!(titles(&read(Some(3))), vec!["a1", "a2", "b1"]);
+        assert_eq!(titles(&read(Some(1))), vec!["a1"]);
+        assert!(read(Some(0)).is_empty());
+        let file = read_dashboard_list(&dir.join("b.yaml"), Some(1), None).unwrap();
+        assert_eq!(titles(&file), vec!["b1"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interpolate_env_substitutes_variables_and_defaults() {
+        std::env::set_var("HERACLES_TEST_SOURCE", "http://prom:9090");
+        std::env::set_var("HERACLES_TEST_EMPTY", "");
+        std::env::remove_var("HERACLES_TEST_UNSET");
+        let file = Path::new("dash.yaml");
+        let interpolate = |contents| interpolate_env(contents, file).unwrap();
+        assert_eq!(interpolate("source: ${HERACLES_TEST_SOURCE}"), "source: http://prom:9090");
+        assert_eq!(interpolate("${HERACLES_TEST_UNSET:-http://a}"), "http://a");
+        assert_eq!(interpolate("${HERACLES_TEST_EMPTY:-http://b}"), "http://b");
+        assert_eq!(interpolate("prefix: ${HERACLES_TEST_EMPTY}"), "prefix: ");
+        assert_eq!(interpolate("${HERACLES_TEST_SOURCE:-http://c}"), "http://prom:9090");
+        // Templates and escapes are left for later.
+        assert_eq!(interpolate("name: ${labels.instance}"), "name: ${labels.instance}");
+        assert_eq!(interpolate("$${HERACLES_TEST_SOURCE}"), "${HERACLES_TEST_SOURCE}");
+        assert_eq!(interpolate("no variables"), "no variables");
+    }
+
+    #[test]
+    fn interpolate_env_errors_name_the_variable_file_and_line() {
+        std::env::remove_var("HERACLES_TEST_MISSING");
+        let file = Path::new("dash.yaml");
+        let err = interpolate_env("title: a\nsource: ${HERACLES_TEST_MISSING}\n", file)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("HERACLES_TEST_MISSING"), "{}", err);
+        assert!(err.contains("dash.yaml line 2"), "{}", err);
+        let err = interpolate_env("source: ${HERACLES", file).err().unwrap().to_string();
+        assert!(err.contains("Unterminated"), "{}", err);
+    }
 }
@@ -0,0 +1,198 @@
+// Copyright 2024 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::{
+    collections::HashMap,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{self, Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use tracing::{debug, error};
+
+/// A fully buffered response that can be handed to every request waiting on it.
+#[derive(Clone)]
+pub struct BufferedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl IntoResponse for BufferedResponse {
+    fn into_response(self) -> Response {
+        let mut response = Response::new(Body::from(self.body));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+    }
+}
+
+type InFlightRequest = Shared<BoxFuture<'static, BufferedResponse>>;
+
+/// Requests that are currently being handled keyed by their full uri.
+#[derive(Clone, Default)]
+pub struct InFlight {
+    requests: Arc<Mutex<HashMap<String, InFlightRequest>>>,
+}
+
+fn error_response() -> BufferedResponse {
+    BufferedResponse {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        headers: HeaderMap::new(),
+        body: Bytes::new(),
+    }
+}
+
+async fn buffer_response(response: Response) -> BufferedResponse {
+    let (parts, body) = response.into_parts();
+    match body::to_bytes(body, usize::MAX).await {
+        Ok(body) => BufferedResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        },
+        Err(err) => {
+            error!(?err, "Unable to buffer response body");
+            error_response()
+        }
+    }
+}
+
+/// Middleware that coalesces identical concurrent GET requests. The first request for a uri
+/// does the work and every request for the same uri that arrives before it finishes gets a
+/// copy of its response.
+pub async fn coalesce(State(inflight): State<InFlight>, req: Request, next: Next) -> Response {
+    if req.method() != Method::GET {
+        return next.run(req).await;
+    }
+    // Requests scoped to different values or asking for different api versions must never
+    // share a response.
+    let mut key = req.uri().to_string();
+    key.push('\n');
+    key.push_str(
+        req.headers()
+            .get(axum::http::header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default(),
+    );
+    for scope in crate::routes::scope_labels() {
+        key.push('\n');
+        key.push_str(
+            req.headers()
+                .get(&scope.header)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default(),
+        );
+    }
+    let shared = {
+        let mut requests = inflight.requests.lock().expect("In flight request lock poisoned");
+        if let Some(shared) = requests.get(&key) {
+            debug!(uri = key, "Joining in flight request");
+            shared.clone()
+        } else {
+            let requests_handle = inflight.requests.clone();
+            let entry_key = key.clone();
+            let shared = async move {
+                // A panicking handler must not poison the shared future for everyone else.
+                let response = match AssertUnwindSafe(next.run(req)).catch_unwind().await {
+                    Ok(response) => buffer_response(response).await,
+                    Err(_) => {
+                        error!(uri = entry_key, "Request handler panicked");
+                        error_response()
+                    }
+                };
+                // Whoever drives the request to completion removes it so later requests
+                // get fresh data.
+                requests_handle
+                    .lock()
+                    .expect("In flight request lock poisoned")
+                    .remove(&entry_key);
+                response
+            }
+            .boxed()
+            .shared();
+            requests.insert(key, shared.clone());
+            shared
+        }
+    };
+    shared.await.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use axum::{http::header, middleware, routing::get, Router};
+
+    use super::*;
+    use crate::query::test_server::serve;
+
+    /// Serves a slow handler behind the coalescing middleware and counts how often it runs.
+    async fn slow_server(hits: Arc<AtomicUsize>) -> String {
+        let handler = move || async move {
+            hits.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            "done"
+        };
+        let router = Router::new()
+            .route("/api", get(handler.clone()).post(handler))
+            .layer(middleware::from_fn_with_state(InFlight::default(), coalesce));
+        serve(router).await
+    }
+
+    /// Sends the requests built against the server url concurrently and returns how many times
+    /// the handler ran.
+    async fn handler_runs(
+        requests: impl FnOnce(&reqwest::Client, &str) -> Vec<reqwest::RequestBuilder>,
+    ) -> usize {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let source = slow_server(hits.clone()).await;
+        let requests = requests(&reqwest::Client::new(), &source);
+        let responses = futures::future::join_all(requests.into_iter().map(|req| req.send())).await;
+        for response in responses {
+            assert_eq!(response.unwrap().text().await.unwrap(), "done");
+        }
+        hits.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn identical_requests_share_one_response() {
+        let get = |client: &reqwest::Client, source: &str, uri: &str| {
+            client.get(format!("{}{}", source, uri))
+        };
+        let same = handler_runs(|c, s| vec![get(c, s, "/api?a=1"), get(c, s, "/api?a=1")]).await;
+        assert_eq!(same, 1);
+        let params = handler_runs(|c, s| vec![get(c, s, "/api?a=1"), get(c, s, "/api?a=2")]).await;
+        assert_eq!(params, 2);
+        let accept = handler_runs(|c, s| {
+            ["application/json", "text/html"]
+                .map(|accept| get(c, s, "/api").header(header::ACCEPT.as_str(), accept))
+                .into()
+        })
+        .await;
+        assert_eq!(accept, 2);
+        let post = handler_runs(|c, s| {
+            (0..2).map(|_| c.post(format!("{}/api", s))).collect()
+        })
+        .await;
+        assert_eq!(post, 2);
+    }
+}
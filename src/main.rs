@@ -12,17 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use anyhow;
-use axum::{self, extract::State, routing::*, Router};
+use axum::{self, extract::State, middleware, routing::*, Router};
 use clap::{self, Parser, ValueEnum};
 use dashboard::{prom_query_data, loki_query_data, Dashboard};
 use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tower_http::compression::CompressionLayer;
 use tower_http::trace::TraceLayer;
 use tracing::Level;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use tracing_subscriber::FmtSubscriber;
 
 mod dashboard;
+mod inflight;
 mod query;
 mod routes;
 
@@ -40,7 +44,7 @@ enum Verbosity {
 struct Cli {
     #[arg(long, help="IP:Port pair to listen on. e.g. 0.0.0.0:8000")]
     pub listen: Option<String>,
-    #[arg(long, help="Location of the configuration file for dashboards.")]
+    #[arg(long, help="Location of the configuration file for dashboards. A directory loads every *.yaml and *.yml file in it in name order.")]
     pub config: PathBuf,
     #[arg(long, value_enum, default_value_t = Verbosity::INFO, help="Logging verbosity")]
     pub verbose: Verbosity,
@@ -48,28 +52,214 @@ struct Cli {
     pub validate: bool,
     #[arg(long, default_value_t = false, help="Do validation offline. Skips testing the queries against their sources.")]
     pub offline: bool,
+    #[arg(long, default_value_t = false, help="Fail validation on warnings instead of just reporting them.")]
+    pub strict: bool,
+    #[arg(long, default_value_t = 1000, help="Line limit for log streams that don't set their own limit.")]
+    pub default_log_limit: usize,
+    #[arg(long, help="Label to mask in every graph and log result. May be repeated.")]
+    pub redact_label: Vec<String>,
+    #[arg(long, default_value_t = false, help="Don't coalesce identical concurrent api requests.")]
+    pub disable_request_coalescing: bool,
+    #[arg(long, default_value_t = false, help="Fail log queries on malformed results instead of skipping them.")]
+    pub strict_parse: bool,
+    #[arg(long, default_value_t = 500, help="Warn during validation when a graph returns more series than this.")]
+    pub max_cardinality: usize,
+    #[arg(long, help="Maximum number of dashboards to load. Dashboards past the limit are skipped.")]
+    pub max_dashboards: Option<usize>,
+    #[arg(long, default_value_t = 4, help="Maximum number of queries to run at once during validation.")]
+    pub validate_concurrency: usize,
+    #[arg(long, value_enum, default_value_t = query::UpstreamHttp2::Auto, help="HTTP/2 for upstream sources. auto negotiates it over TLS and falls back to HTTP/1.1.")]
+    pub upstream_http2: query::UpstreamHttp2,
+    #[arg(long, default_value_t = query::DEFAULT_POST_QUERY_LENGTH, help="PromQL queries longer than this are sent with POST instead of GET.")]
+    pub post_query_length: usize,
+    #[arg(long, help="Directory dashboard custom_js files are loaded from. Files outside of it are refused.")]
+    pub assets_dir: Option<PathBuf>,
+    #[arg(long, help="Truncate label values longer than this for graphs and logs that don't set their own max_label_len.")]
+    pub max_label_len: Option<usize>,
+    #[arg(long, help="<label>=<header>. Adds a label=\"<header value>\" matcher to every prometheus query through its FILTERS placeholder. Requests without the header and graphs without the placeholder in every selector are refused. Log streams can't be scoped so they are refused while this is set. May be repeated.")]
+    pub scope_label: Vec<routes::ScopeLabel>,
+    #[arg(long, help="Most upstream queries per minute for dashboards that don't set their own query_budget. Results from the cache don't count. Unlimited by default.")]
+    pub query_budget: Option<u32>,
+    #[arg(long, default_value_t = query::DEFAULT_MAX_POINTS, help="Range query steps are raised so no series has more points than this. Capped at prometheus' limit of 11000. Dashboards with strict_step keep their step.")]
+    pub max_points: i64,
+    #[arg(long, default_value_t = DEFAULT_CACHE_SECONDS, help="Seconds to cache identical prometheus query results for dashboards that don't set their own cache_seconds. 0 disables caching.")]
+    pub cache_seconds: u64,
+    #[arg(long, default_value_t = query::DEFAULT_CACHE_MAX_ENTRIES, help="Most query results to cache. The least recently used are evicted first.")]
+    pub cache_max_entries: usize,
+    #[arg(long, help="Environment variable holding the bearer token for the /admin endpoints. They are disabled without it.")]
+    pub admin_token_env: Option<String>,
 }
 
-async fn validate(dash: &Dashboard) -> anyhow::Result<()> {
-    if let Some(ref graphs) = dash.graphs {
-        for graph in graphs.iter() {
-            let data = prom_query_data(graph, &dash, None, &None).await;
-            if data.is_err() {
-                error!(err=?data, "Invalid dashboard graph query or queries");
+/// Default for the --cache-seconds flag. Long enough to absorb many tabs or embeds loading the
+/// same graph at once.
+const DEFAULT_CACHE_SECONDS: u64 = 5;
+
+/// A graph or log stream to validate.
+#[derive(Clone, Copy, Debug)]
+enum Panel {
+    Graph(usize),
+    Log(usize),
+}
+
+impl Panel {
+    /// Describes the panel for error messages. e.g. `dashboard 1 graph "Node cpu"`.
+    fn describe(&self, config: &[Dashboard], dash_idx: usize) -> String {
+        let dash = &config[dash_idx];
+        match self {
+            Panel::Graph(idx) => format!(
+                "dashboard {} graph {:?}",
+                dash_idx,
+                dash.graphs.as_ref().expect("No graphs in this dashboard")[*idx].title
+            ),
+            Panel::Log(idx) => format!(
+                "dashboard {} log {:?}",
+                dash_idx,
+                dash.logs.as_ref().expect("No logs in this dashboard")[*idx].title
+            ),
+        }
+    }
+}
+
+/// Runs a single panel's queries against their sources. Graphs over the cardinality limit are
+/// only an error in strict mode.
+async fn validate_panel(
+    config: Arc<Vec<Dashboard>>,
+    dash_idx: usize,
+    panel: Panel,
+    strict: bool,
+    max_cardinality: usize,
+) -> anyhow::Result<()> {
+    let dash = &config[dash_idx];
+    match panel {
+        Panel::Graph(idx) => {
+            let graph = &dash.graphs.as_ref().expect("No graphs in this dashboard")[idx];
+            let origin = format!("validate dashboard={} graph={}", dash_idx, idx);
+            let data = prom_query_data(graph, dash, None, &None, None, &[], &origin).await?;
+            let series: usize = data.plots.iter().map(|plot| plot.series_count()).sum();
+            if series > max_cardinality {
+                warn!(
+                    dashboard = dash_idx,
+                    graph = idx,
+                    series,
+                    max_cardinality,
+                    "Graph returns more series than the cardinality limit"
+                );
+                if strict {
+                    return Err(anyhow::anyhow!(
+                        "{} series is over the cardinality limit of {}",
+                        series,
+                        max_cardinality
+                    ));
+                }
             }
-            let _ = data?;
+        }
+        Panel::Log(idx) => {
+            let log = &dash.logs.as_ref().expect("No logs in this dashboard")[idx];
+            let origin = format!("validate dashboard={} log={}", dash_idx, idx);
+            loki_query_data(log, dash, None, &origin).await?;
         }
     }
-    if let Some(ref logs) = dash.logs {
-        for log in logs.iter() {
-            let data = loki_query_data(log, dash, None).await;
-            if data.is_err() {
-                error!(err=?data, "Invalid dashboard loki query or queries");
+    Ok(())
+}
+
+/// Validates every panel of every dashboard with at most `concurrency` queries in flight so a
+/// large config doesn't stampede the sources. All failures are logged and counted rather than
+/// stopping at the first one.
+async fn validate(
+    config: Arc<Vec<Dashboard>>,
+    strict: bool,
+    max_cardinality: usize,
+    concurrency: usize,
+) -> anyhow::Result<()> {
+    // (passed, failed) per dashboard.
+    let mut counts = vec![(0usize, 0usize); config.len()];
+    let mut failures = Vec::new();
+    let mut panels = Vec::new();
+    for (dash_idx, dash) in config.iter().enumerate() {
+        let warnings = dash.span_warnings();
+        for warning in warnings.iter() {
+            warn!(dashboard = dash.title, warning, "Dashboard span may not be servable by its sources");
+        }
+        if strict && !warnings.is_empty() {
+            error!(dashboard = dash_idx, warnings = warnings.len(), "Dashboard has span warnings");
+            counts[dash_idx].1 += 1;
+            failures.push(format!("dashboard {} has {} span warnings", dash_idx, warnings.len()));
+        }
+        let graphs = dash.graphs.as_ref().map(|g| g.len()).unwrap_or(0);
+        let logs = dash.logs.as_ref().map(|l| l.len()).unwrap_or(0);
+        panels.extend((0..graphs).map(|idx| (dash_idx, Panel::Graph(idx))));
+        panels.extend((0..logs).map(|idx| (dash_idx, Panel::Log(idx))));
+    }
+    let mut set = JoinSet::new();
+    let mut panels = panels.into_iter();
+    loop {
+        while set.len() < concurrency.max(1) {
+            let Some((dash_idx, panel)) = panels.next() else {
+                break;
+            };
+            let config = config.clone();
+            set.spawn(async move {
+                let result = validate_panel(config, dash_idx, panel, strict, max_cardinality).await;
+                (dash_idx, panel, result)
+            });
+        }
+        match set.join_next().await {
+            Some(Ok((dash_idx, _, Ok(())))) => counts[dash_idx].0 += 1,
+            Some(Ok((dash_idx, panel, Err(err)))) => {
+                let panel = panel.describe(&config, dash_idx);
+                error!(panel, ?err, "Invalid dashboard query or queries");
+                counts[dash_idx].1 += 1;
+                failures.push(format!("{}: {:#}", panel, err));
             }
-            let _ = data?;
+            Some(Err(err)) => {
+                error!(?err, "Validation task panicked");
+                failures.push(format!("validation task panicked: {}", err));
+            }
+            None => break,
+        }
+    }
+    // Panicked tasks aren't in the per dashboard counts.
+    let panicked = failures.len() - counts.iter().map(|(_, failed)| failed).sum::<usize>();
+    let mut total = panicked;
+    for (dash_idx, (dash_passed, dash_failed)) in counts.iter().enumerate() {
+        info!(
+            dashboard = dash_idx,
+            title = config[dash_idx].title,
+            passed = dash_passed,
+            failed = dash_failed,
+            "Dashboard validation summary"
+        );
+        total += dash_passed + dash_failed;
+    }
+    if !failures.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} of {} checks failed validation:\n{}",
+            failures.len(),
+            total,
+            failures.join("\n")
+        ));
+    }
+    Ok(())
+}
+
+/// Runs `offline_validate` on every dashboard and reports all of the failures together.
+fn validate_offline(config: &[Dashboard]) -> anyhow::Result<()> {
+    let mut failures = Vec::new();
+    for (dash_idx, dash) in config.iter().enumerate() {
+        if let Err(err) = dashboard::offline_validate(dash) {
+            error!(dashboard = dash_idx, title = dash.title, %err, "Invalid dashboard");
+            failures.push(format!("dashboard {} ({}):\n{}", dash_idx, dash.title, err));
         }
     }
-    return Ok(());
+    if !failures.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} of {} dashboards failed offline validation:\n{}",
+            failures.len(),
+            config.len(),
+            failures.join("\n")
+        ));
+    }
+    Ok(())
 }
 
 #[tokio::main]
@@ -86,23 +276,81 @@ async fn main() -> anyhow::Result<()> {
         subscriber_builder.with_writer(std::io::stderr).finish(),
     )
     .expect("setting default subscriber failed");
+    query::set_upstream_http2(args.upstream_http2);
+    query::set_post_query_length(args.post_query_length);
+    query::set_max_points(args.max_points);
+    query::set_cache_max_entries(args.cache_max_entries);
+    routes::set_config_path(&args.config);
+    routes::set_scope_labels(args.scope_label.clone());
+    if let Some(ref var) = args.admin_token_env {
+        let token = std::env::var(var)
+            .map_err(|err| anyhow::anyhow!("Admin token variable {}: {}", var, err))?;
+        if token.is_empty() {
+            return Err(anyhow::anyhow!("Admin token variable {} is empty", var));
+        }
+        routes::set_admin_token(token);
+    }
 
-    let config = std::sync::Arc::new(dashboard::read_dashboard_list(args.config.as_path())?);
+    let mut dashboards = dashboard::read_dashboard_list(
+        args.config.as_path(),
+        args.max_dashboards,
+        args.assets_dir.as_deref(),
+    )?;
+    let playlists = dashboard::read_playlists(args.config.as_path(), &dashboards)?;
+    for dash in dashboards.iter_mut() {
+        dash.set_default_log_limit(args.default_log_limit);
+        dash.add_redacted_labels(&args.redact_label);
+        if let Some(max) = args.max_label_len {
+            dash.set_default_max_label_len(max);
+        }
+        if args.strict_parse {
+            dash.set_strict_parse();
+        }
+        if args.validate {
+            // Validation runs every query once so budgets would only get in the way.
+            dash.query_budget = Some(0);
+        } else if let Some(limit) = args.query_budget {
+            dash.set_default_query_budget(limit);
+        }
+        dash.set_default_cache_seconds(args.cache_seconds);
+    }
+    let config = Arc::new(dashboards);
 
     if args.validate {
+        if config.is_empty() {
+            warn!(config = ?args.config, "The config has no dashboards");
+        }
+        // Structural problems like an invalid span end would otherwise only show up as silent
+        // fallbacks when the queries run.
+        validate_offline(&config)?;
+        info!("All dashboards are structurally valid");
         if !args.offline {
-            for dash in config.iter() {
-                validate(&dash).await?;
-                info!("All Queries successfully run against source");
-                return Ok(());
-            }
+            validate(
+                config.clone(),
+                args.strict,
+                args.max_cardinality,
+                args.validate_concurrency,
+            )
+            .await?;
+            info!("All Queries successfully run against source");
         }
+        return Ok(());
+    }
+    let mut api_routes = routes::mk_api_routes(config.clone());
+    if !args.disable_request_coalescing {
+        api_routes = api_routes.layer(middleware::from_fn_with_state(
+            inflight::InFlight::default(),
+            inflight::coalesce,
+        ));
     }
+    // Outside of the coalescing so a shared response is only ever compressed for clients that
+    // asked for it. Responses under the default 32 byte threshold are sent as is.
+    api_routes = api_routes.layer(CompressionLayer::new());
     let router = Router::new()
         // JSON api endpoints
         .nest("/js", routes::mk_js_routes(config.clone()))
         .nest("/static", routes::mk_static_routes(config.clone()))
-        .nest("/api", routes::mk_api_routes(config.clone()))
+        .nest("/api", api_routes)
         // HTMX ui component endpoints
         .nest("/ui", routes::mk_ui_routes(config.clone()))
         .route(
@@ -113,6 +361,18 @@ async fn main() -> anyhow::Result<()> {
             "/embed/dash/:dash_idx/log/:graph_idx",
             get(routes::log_embed).with_state(State(config.clone())),
         )
+        .route(
+            "/playlist/:name",
+            get(routes::playlist).with_state(Arc::new(playlists)),
+        )
+        // Orchestrator probes
+        .route("/healthz", get(routes::healthz))
+        .route("/readyz", get(routes::readyz).with_state(config.clone()))
+        .route("/metrics", get(routes::metrics))
+        .route(
+            "/admin/cache/invalidate",
+            post(routes::invalidate_cache).with_state(config.clone()),
+        )
         .route("/dash/:dash_idx", get(routes::dashboard_direct))
         .route("/", get(routes::index).with_state(State(config.clone())))
         .layer(TraceLayer::new_for_http())
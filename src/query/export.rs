@@ -0,0 +1,125 @@
+// Copyright 2024 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arrow_array::{
+    builder::{Float64Builder, MapBuilder, StringBuilder, UInt32Builder},
+    ArrayRef, RecordBatch,
+};
+use arrow_schema::{Field, Schema};
+use parquet::arrow::ArrowWriter;
+
+use super::{DataPoint, MetricsQueryResult};
+
+/// Collects rows for the parquet export. Labels go in a single map column since series in the
+/// same graph can have different label sets.
+struct ParquetRows {
+    plot: UInt32Builder,
+    timestamp: Float64Builder,
+    value: Float64Builder,
+    labels: MapBuilder<StringBuilder, StringBuilder>,
+}
+
+impl ParquetRows {
+    fn new() -> Self {
+        Self {
+            plot: UInt32Builder::new(),
+            timestamp: Float64Builder::new(),
+            value: Float64Builder::new(),
+            labels: MapBuilder::new(None, StringBuilder::new(), StringBuilder::new()),
+        }
+    }
+
+    fn push(
+        &mut self,
+        plot: u32,
+        labels: &HashMap<String, String>,
+        point: &DataPoint,
+    ) -> anyhow::Result<()> {
+        self.plot.append_value(plot);
+        self.timestamp.append_value(point.timestamp);
+        self.value.append_value(point.value);
+        // Sort the labels so every row for a series has the same entry order.
+        let mut labels = labels.iter().collect::<Vec<(&String, &String)>>();
+        labels.sort();
+        for (k, v) in labels {
+            self.labels.keys().append_value(k);
+            self.labels.values().append_value(v);
+        }
+        self.labels.append(true)?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> anyhow::Result<RecordBatch> {
+        let columns: Vec<(&str, ArrayRef)> = vec![
+            ("plot", Arc::new(self.plot.finish())),
+            ("timestamp", Arc::new(self.timestamp.finish())),
+            ("value", Arc::new(self.value.finish())),
+            ("labels", Arc::new(self.labels.finish())),
+        ];
+        let schema = Schema::new(
+            columns
+                .iter()
+                .map(|(name, array)| Field::new(*name, array.data_type().clone(), false))
+                .collect::<Vec<Field>>(),
+        );
+        Ok(RecordBatch::try_new(
+            Arc::new(schema),
+            columns.into_iter().map(|(_, array)| array).collect(),
+        )?)
+    }
+}
+
+/// Encodes the plots of a graph as a parquet file with one row per data point. The columns are
+/// `plot` (the subplot index), `timestamp` (unix seconds), `value`, and `labels` (a map of
+/// label name to value). Histogram points are written with their observation count as the value.
+pub fn metrics_to_parquet(plots: &[MetricsQueryResult]) -> anyhow::Result<Vec<u8>> {
+    let mut rows = ParquetRows::new();
+    for (idx, plot) in plots.iter().enumerate() {
+        let idx = idx as u32;
+        match plot {
+            MetricsQueryResult::Series(series) => {
+                for (labels, _, points) in series {
+                    for point in points {
+                        rows.push(idx, labels, point)?;
+                    }
+                }
+            }
+            MetricsQueryResult::Scalar(scalars) => {
+                for (labels, _, point) in scalars {
+                    rows.push(idx, labels, point)?;
+                }
+            }
+            MetricsQueryResult::Histogram(histograms) => {
+                for (labels, _, points) in histograms {
+                    for point in points {
+                        let point = DataPoint {
+                            timestamp: point.timestamp,
+                            value: point.count,
+                            color: None,
+                        };
+                        rows.push(idx, labels, &point)?;
+                    }
+                }
+            }
+        }
+    }
+    let batch = rows.finish()?;
+    let mut buf = Vec::new();
+    let mut writer = ArrowWriter::try_new(&mut buf, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(buf)
+}
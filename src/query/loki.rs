@@ -15,11 +15,17 @@ use std::collections::HashMap;
 
 use anyhow::Result;
 use chrono::prelude::*;
-use reqwest;
+use reqwest::{
+    header::{AUTHORIZATION, RETRY_AFTER},
+    StatusCode,
+};
 use serde::{Deserialize, Serialize};
-use tracing::{debug, error};
+use tracing::{debug, error, info};
 
-use super::{LogLine, LogQueryResult, QueryType, TimeSpan};
+use super::{
+    http_client, observe_query, with_timeout, LogLine, LogQueryResult, QueryBudget, QueryType,
+    SourceAuth, TimeSpan, DEFAULT_QUERY_TIMEOUT_SECONDS, ORG_ID_HEADER, ORIGIN_HEADER,
+};
 
 // TODO(jwall): Should I allow non stream returns?
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -62,28 +68,65 @@ pub struct LokiData {
     //stats: // TODO
 }
 
-pub fn loki_to_sample(data: LokiData) -> LogQueryResult {
+/// Returned when loki rate limits a query. `retry_after` is the number of seconds from the
+/// `Retry-After` header if loki sent one.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: Option<u64>,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.retry_after {
+            Some(secs) => write!(f, "Loki rate limited the query. Retry after {} seconds", secs),
+            None => write!(f, "Loki rate limited the query"),
+        }
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+fn parse_timestamp(timestamp: &str) -> Result<f64> {
+    timestamp
+        .parse::<f64>()
+        .map_err(|e| anyhow::anyhow!("Invalid timestamp {}: {}", timestamp, e))
+}
+
+/// Converts a loki response into log lines. Malformed results are logged and skipped unless
+/// `strict` is set in which case the first one is returned as an error.
+pub fn loki_to_sample(data: LokiData, strict: bool) -> Result<LogQueryResult> {
     match data.result_type {
         ResultType::Vector => {
             let mut values = Vec::with_capacity(data.result.len());
             for result in data.result {
                 if let Some(value) = result.value {
-                    values.push((
-                        result.labels,
-                        LogLine {
-                            timestamp: value.0.parse::<f64>().expect("Invalid f64 type"),
-                            line: value.1,
-                        },
-                    ));
+                    match parse_timestamp(&value.0) {
+                        Ok(timestamp) => values.push((
+                            result.labels,
+                            LogLine {
+                                timestamp,
+                                line: value.1,
+                                time_display: String::new(),
+                            },
+                        )),
+                        Err(e) if strict => return Err(e),
+                        Err(e) => error!(err = ?e, "Skipping log line with invalid timestamp"),
+                    }
                 } else {
                     error!(
                         ?result,
                         "Invalid LokiResult: No value field when result type is {:?}",
                         data.result_type,
                     );
+                    if strict {
+                        return Err(anyhow::anyhow!(
+                            "Invalid LokiResult: No value field when result type is {:?}",
+                            data.result_type
+                        ));
+                    }
                 }
             }
-            LogQueryResult::StreamInstant(values)
+            Ok(LogQueryResult::StreamInstant(values))
         }
         // Stream types are nanoseconds. // Matrix types are seconds
         ResultType::Matrix | ResultType::Streams => {
@@ -91,25 +134,34 @@ pub fn loki_to_sample(data: LokiData) -> LogQueryResult {
             let multiple = (if data.result_type == ResultType::Matrix { 1000000 } else { 1 }) as f64;
             for result in data.result {
                 if let Some(value) = result.values {
-                    values.push((
-                        result.labels,
-                        value
-                            .into_iter()
-                            .map(|(timestamp, line)| LogLine {
-                                timestamp: multiple * timestamp.parse::<f64>().expect("Invalid f64 type"),
+                    let mut lines = Vec::with_capacity(value.len());
+                    for (timestamp, line) in value {
+                        match parse_timestamp(&timestamp) {
+                            Ok(timestamp) => lines.push(LogLine {
+                                timestamp: multiple * timestamp,
                                 line,
-                            })
-                            .collect(),
-                    ));
+                                time_display: String::new(),
+                            }),
+                            Err(e) if strict => return Err(e),
+                            Err(e) => error!(err = ?e, "Skipping log line with invalid timestamp"),
+                        }
+                    }
+                    values.push((result.labels, lines));
                 } else {
                     error!(
                         ?result,
                         "Invalid LokiResult: No values field when result type is {:?}",
                         data.result_type,
                     );
+                    if strict {
+                        return Err(anyhow::anyhow!(
+                            "Invalid LokiResult: No values field when result type is {:?}",
+                            data.result_type
+                        ));
+                    }
                 }
             }
-            LogQueryResult::Stream(values)
+            Ok(LogQueryResult::Stream(values))
         }
     }
 }
@@ -120,10 +172,18 @@ pub struct LokiConn<'conn> {
     span: Option<TimeSpan>,
     query_type: QueryType,
     limit: Option<usize>,
+    origin: &'conn str,
+    api_prefix: &'conn str,
+    auth: Option<&'conn SourceAuth>,
+    org_id: Option<&'conn str>,
+    timeout: std::time::Duration,
+    budget: Option<(&'conn QueryBudget, u32)>,
 }
 
-const SCALAR_API_PATH: &'static str = "/loki/api/v1/query";
-const RANGE_API_PATH: &'static str = "/loki/api/v1/query_range";
+/// Where the loki api lives under the source url by default.
+pub const DEFAULT_API_PREFIX: &str = "/loki/api/v1";
+const SCALAR_API_PATH: &str = "/query";
+const RANGE_API_PATH: &str = "/query_range";
 
 impl<'conn> LokiConn<'conn> {
     pub fn new<'a: 'conn>(url: &'a str, query: &'a str, query_type: QueryType) -> Self {
@@ -133,9 +193,62 @@ impl<'conn> LokiConn<'conn> {
             query_type,
             span: None,
             limit: None,
+            origin: "",
+            api_prefix: DEFAULT_API_PREFIX,
+            auth: None,
+            org_id: None,
+            timeout: std::time::Duration::from_secs(DEFAULT_QUERY_TIMEOUT_SECONDS),
+            budget: None,
         }
     }
 
+    /// Counts the query against the budget's limit before it is sent.
+    pub fn with_budget(mut self, budget: Option<(&'conn QueryBudget, u32)>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Overrides the api path prefix for sources that expose the loki api somewhere other
+    /// than DEFAULT_API_PREFIX.
+    pub fn with_api_prefix(mut self, api_prefix: &'conn str) -> Self {
+        self.api_prefix = api_prefix;
+        self
+    }
+
+    /// How long the upstream request gets before failing with QueryTimedOut.
+    pub fn with_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the X-Scope-OrgID tenant header on every query.
+    pub fn with_org_id(mut self, org_id: Option<&'conn str>) -> Self {
+        self.org_id = org_id;
+        self
+    }
+
+    /// Sends the credentials with every query.
+    pub fn with_auth(mut self, auth: Option<&'conn SourceAuth>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Appends the api path to the source url. Any base path in the source url is kept.
+    fn api_url(&self, path: &str) -> String {
+        format!(
+            "{}/{}{}",
+            self.url.trim_end_matches('/'),
+            self.api_prefix.trim_matches('/'),
+            path
+        )
+    }
+
+    /// Tags the query with where it originated for audit logging.
+    pub fn with_origin(mut self, origin: &'conn str) -> Self {
+        self.origin = origin;
+        self
+    }
+
     pub fn with_limit(mut self, limit: usize) -> Self {
         self.limit = Some(limit);
         self
@@ -157,11 +270,26 @@ impl<'conn> LokiConn<'conn> {
 
     pub async fn get_results(&self) -> Result<LokiResponse> {
         let url = match self.query_type {
-            QueryType::Scalar => format!("{}{}", self.url, SCALAR_API_PATH),
-            QueryType::Range => format!("{}{}", self.url, RANGE_API_PATH),
+            QueryType::Scalar => self.api_url(SCALAR_API_PATH),
+            QueryType::Range => self.api_url(RANGE_API_PATH),
         };
-        let client = reqwest::Client::new();
-        let mut req = client.get(url).query(&[("query", self.query)]);
+        let client = http_client()?;
+        info!(
+            origin = self.origin,
+            source = self.url,
+            query = self.query,
+            "Running upstream loki query"
+        );
+        let mut req = client
+            .get(url)
+            .header(ORIGIN_HEADER, self.origin)
+            .query(&[("query", self.query)]);
+        if let Some(auth) = self.auth {
+            req = req.header(AUTHORIZATION, auth.header_value()?);
+        }
+        if let Some(org_id) = self.org_id {
+            req = req.header(ORG_ID_HEADER, org_id);
+        }
         debug!(?req, "Building loki reqwest client");
         if self.limit.is_some() {
             debug!(?req, "adding limit");
@@ -186,7 +314,136 @@ impl<'conn> LokiConn<'conn> {
             ]);
         }
 
+        if let Some((budget, limit)) = self.budget {
+            budget.take(limit)?;
+        }
         debug!(?req, "Sending request");
-        Ok(req.send().await?.json().await?)
+        let query = with_timeout(self.timeout, async {
+            let response = req.send().await?;
+            if response.status() == StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = response
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<u64>().ok());
+                return Err(RateLimited { retry_after }.into());
+            }
+            Ok(response.json().await?)
+        });
+        observe_query(self.url, &self.query_type, query).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::header, routing::get, Router};
+
+    use super::*;
+    use crate::query::test_server::{gzip, serve};
+
+    const LOKI_STREAMS: &str = r#"{"status":"success","data":{"resultType":"streams","result":[{"stream":{"job":"api"},"values":[["1700000000000000000","first"],["1700000001000000000","second"]]}]}}"#;
+
+    #[tokio::test]
+    async fn gzip_encoded_responses_are_decoded() {
+        let source = serve(Router::new().route(
+            "/loki/api/v1/query_range",
+            get(|| async {
+                (
+                    [
+                        (header::CONTENT_TYPE, "application/json"),
+                        (header::CONTENT_ENCODING, "gzip"),
+                    ],
+                    gzip(LOKI_STREAMS.as_bytes()),
+                )
+            }),
+        ))
+        .await;
+        let conn = LokiConn::new(&source, "{job=\"api\"}", QueryType::Range);
+        let response = conn.get_results().await.expect("gzip response should parse");
+        assert_eq!(response.status, "success");
+        match loki_to_sample(response.data, true).expect("valid streams") {
+            LogQueryResult::Stream(streams) => {
+                assert_eq!(streams.len(), 1);
+                assert_eq!(streams[0].1.len(), 2);
+            }
+            _ => panic!("Expected streams"),
+        }
+    }
+
+    #[test]
+    fn api_url_keeps_the_source_base_path() {
+        let url = |source, prefix| {
+            let conn = LokiConn::new(source, "{}", QueryType::Range);
+            let conn = match prefix {
+                Some(prefix) => conn.with_api_prefix(prefix),
+                None => conn,
+            };
+            conn.api_url(RANGE_API_PATH)
+        };
+        assert_eq!(url("http://loki:3100", None), "http://loki:3100/loki/api/v1/query_range");
+        assert_eq!(url("http://loki:3100/", None), "http://loki:3100/loki/api/v1/query_range");
+        assert_eq!(
+            url("https://host/logs/", None),
+            "https://host/logs/loki/api/v1/query_range"
+        );
+        assert_eq!(
+            url("https://host/logs", Some("/api/v1/")),
+            "https://host/logs/api/v1/query_range"
+        );
+    }
+
+    #[tokio::test]
+    async fn queries_are_sent_under_the_source_base_path() {
+        let source = serve(Router::new().route(
+            "/logs/loki/api/v1/query_range",
+            get(|| async { ([(header::CONTENT_TYPE, "application/json")], LOKI_STREAMS) }),
+        ))
+        .await;
+        let source = format!("{}/logs", source);
+        let conn = LokiConn::new(&source, "{job=\"api\"}", QueryType::Range);
+        let response = conn.get_results().await.expect("prefixed query should succeed");
+        assert_eq!(response.status, "success");
+    }
+
+    /// Streams with a line that has an invalid timestamp and a stream without values.
+    const MALFORMED_STREAMS: &str = r#"{"resultType":"streams","result":[{"stream":{"job":"api"},"values":[["1700000000000000000","good"],["yesterday","bad"]]},{"stream":{"job":"db"}}]}"#;
+    /// Vector results with an invalid timestamp and one without a value.
+    const MALFORMED_VECTOR: &str = r#"{"resultType":"vector","result":[{"metric":{"job":"api"},"value":["1700000000","1"]},{"metric":{"job":"db"},"value":["never","2"]},{"metric":{"job":"web"}}]}"#;
+
+    fn parse_data(json: &str) -> LokiData {
+        serde_json::from_str(json).expect("Invalid test data")
+    }
+
+    #[test]
+    fn lenient_parsing_skips_malformed_results() {
+        match loki_to_sample(parse_data(MALFORMED_STREAMS), false).unwrap() {
+            LogQueryResult::Stream(streams) => {
+                assert_eq!(streams.len(), 1);
+                assert_eq!(streams[0].0["job"], "api");
+                let lines = streams[0].1.iter().map(|l| l.line.as_str()).collect::<Vec<_>>();
+                assert_eq!(lines, vec!["good"]);
+            }
+            _ => panic!("Expected streams"),
+        }
+        match loki_to_sample(parse_data(MALFORMED_VECTOR), false).unwrap() {
+            LogQueryResult::StreamInstant(values) => {
+                assert_eq!(values.len(), 1);
+                assert_eq!(values[0].0["job"], "api");
+            }
+            _ => panic!("Expected an instant result"),
+        }
+    }
+
+    #[test]
+    fn strict_parsing_fails_on_malformed_results() {
+        let err = loki_to_sample(parse_data(MALFORMED_STREAMS), true).err().unwrap();
+        assert!(err.to_string().contains("yesterday"), "{}", err);
+        let err = loki_to_sample(parse_data(MALFORMED_VECTOR), true).err().unwrap();
+        assert!(err.to_string().contains("never"), "{}", err);
+        let missing_values = r#"{"resultType":"streams","result":[{"stream":{"job":"db"}}]}"#;
+        let err = loki_to_sample(parse_data(missing_values), true).err().unwrap();
+        assert!(err.to_string().contains("No values field"), "{}", err);
+        let valid = r#"{"resultType":"streams","result":[{"stream":{"job":"api"},"values":[["1700000000000000000","good"]]}]}"#;
+        assert!(loki_to_sample(parse_data(valid), true).is_ok());
     }
 }
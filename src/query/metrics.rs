@@ -0,0 +1,138 @@
+// Copyright 2024 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use super::QueryType;
+
+/// Upper bounds in seconds of the query_duration_seconds buckets. The same defaults the
+/// prometheus client libraries use.
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counts for the upstream queries of one source and query type.
+#[derive(Default)]
+struct QueryStats {
+    total: u64,
+    errors: u64,
+    /// Non cumulative count of durations per DURATION_BUCKETS entry. The last one is +Inf.
+    buckets: [u64; DURATION_BUCKETS.len() + 1],
+    duration_sum: f64,
+}
+
+static QUERY_STATS: OnceLock<Mutex<BTreeMap<(String, &'static str), QueryStats>>> =
+    OnceLock::new();
+
+fn query_stats() -> &'static Mutex<BTreeMap<(String, &'static str), QueryStats>> {
+    QUERY_STATS.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+impl QueryType {
+    /// The query_type label value.
+    fn label(&self) -> &'static str {
+        match self {
+            QueryType::Range => "Range",
+            QueryType::Scalar => "Scalar",
+        }
+    }
+}
+
+/// Runs an upstream query and records its duration and whether it failed. Timeouts count as
+/// errors.
+pub async fn observe_query<T>(
+    source: &str,
+    query_type: &QueryType,
+    query: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let start = Instant::now();
+    let result = query.await;
+    let seconds = start.elapsed().as_secs_f64();
+    let mut stats = query_stats().lock().expect("Query stats lock poisoned");
+    let stats = stats
+        .entry((source.to_string(), query_type.label()))
+        .or_default();
+    stats.total += 1;
+    if result.is_err() {
+        stats.errors += 1;
+    }
+    let bucket = DURATION_BUCKETS
+        .iter()
+        .position(|upper| seconds <= *upper)
+        .unwrap_or(DURATION_BUCKETS.len());
+    stats.buckets[bucket] += 1;
+    stats.duration_sum += seconds;
+    result
+}
+
+/// Escapes a label value for the text exposition format.
+fn escape_exposition_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders the upstream query metrics in the prometheus text exposition format.
+pub fn render_query_metrics() -> String {
+    let stats = query_stats().lock().expect("Query stats lock poisoned");
+    let labels = stats
+        .keys()
+        .map(|(source, query_type)| {
+            format!(
+                "source=\"{}\",query_type=\"{}\"",
+                escape_exposition_value(source),
+                query_type
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut out = String::new();
+    out.push_str("# HELP query_total Upstream queries sent.\n");
+    out.push_str("# TYPE query_total counter\n");
+    for (labels, stats) in labels.iter().zip(stats.values()) {
+        let _ = writeln!(out, "query_total{{{}}} {}", labels, stats.total);
+    }
+    out.push_str("# HELP query_errors_total Upstream queries that failed or timed out.\n");
+    out.push_str("# TYPE query_errors_total counter\n");
+    for (labels, stats) in labels.iter().zip(stats.values()) {
+        let _ = writeln!(out, "query_errors_total{{{}}} {}", labels, stats.errors);
+    }
+    out.push_str("# HELP query_duration_seconds Upstream query latency.\n");
+    out.push_str("# TYPE query_duration_seconds histogram\n");
+    for (labels, stats) in labels.iter().zip(stats.values()) {
+        let mut cumulative = 0;
+        for (upper, count) in DURATION_BUCKETS.iter().zip(stats.buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "query_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                labels, upper, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "query_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+            labels, stats.total
+        );
+        let _ = writeln!(
+            out,
+            "query_duration_seconds_sum{{{}}} {}",
+            labels, stats.duration_sum
+        );
+        let _ = writeln!(out, "query_duration_seconds_count{{{}}} {}", labels, stats.total);
+    }
+    out
+}
@@ -12,17 +12,237 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
 
+use anyhow::Context;
+use base64::prelude::*;
 use chrono::prelude::*;
+use reqwest::header::HeaderValue;
 use serde::{Deserialize, Serialize};
+use tracing::error;
 
-use crate::dashboard::PlotConfig;
+use crate::dashboard::{threshold_color, PlotConfig, Threshold};
 
+mod export;
 mod loki;
+mod metrics;
 mod prom;
+#[cfg(test)]
+pub mod test_server;
 
-#[derive(Deserialize, Clone, Debug)]
+/// Header used to tell upstreams which dashboard panel a query came from.
+/// The value has the form `dashboard=<idx> graph=<idx>` or `dashboard=<idx> log=<idx>`.
+pub const ORIGIN_HEADER: &str = "X-Heracles-Origin";
+/// Tenant header for multi-tenant Mimir, Cortex, and Loki.
+pub const ORG_ID_HEADER: &str = "X-Scope-OrgID";
+
+/// Which HTTP version to speak to upstream sources.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum UpstreamHttp2 {
+    /// Negotiate HTTP/2 over TLS and fall back to HTTP/1.1 when the source doesn't offer it.
+    /// Plain http sources always use HTTP/1.1.
+    #[default]
+    Auto,
+    /// Speak HTTP/2 to every source without negotiating, including plain http ones. Sources
+    /// that don't support HTTP/2 will fail.
+    PriorKnowledge,
+    /// Only use HTTP/1.1.
+    Off,
+}
+
+static UPSTREAM_HTTP2: OnceLock<UpstreamHttp2> = OnceLock::new();
+static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Sets the HTTP version used for upstream queries. Must be called before the first query.
+pub fn set_upstream_http2(mode: UpstreamHttp2) {
+    if UPSTREAM_HTTP2.set(mode).is_err() {
+        error!(?mode, "Upstream http2 mode was already set");
+    }
+}
+
+/// Returns the reqwest client shared by all upstream queries so connections get reused.
+/// Compressed responses are decoded transparently so gzip or brotli encoding upstreams parse
+/// like uncompressed ones.
+pub fn http_client() -> reqwest::Result<reqwest::Client> {
+    if let Some(client) = HTTP_CLIENT.get() {
+        return Ok(client.clone());
+    }
+    let mut builder = reqwest::Client::builder().gzip(true).brotli(true);
+    builder = match UPSTREAM_HTTP2.get().copied().unwrap_or_default() {
+        UpstreamHttp2::Auto => builder,
+        UpstreamHttp2::PriorKnowledge => builder.http2_prior_knowledge(),
+        UpstreamHttp2::Off => builder.http1_only(),
+    };
+    let client = builder.build()?;
+    // Two queries racing to build the first client is harmless. The loser's client is dropped.
+    Ok(HTTP_CLIENT.get_or_init(|| client).clone())
+}
+
+/// How long an upstream query gets when the dashboard doesn't set a timeout.
+pub const DEFAULT_QUERY_TIMEOUT_SECONDS: u64 = 30;
+
+/// Returned when an upstream query takes longer than its timeout.
+#[derive(Debug)]
+pub struct QueryTimedOut {
+    pub timeout: std::time::Duration,
+}
+
+impl std::fmt::Display for QueryTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Upstream query timed out after {:?}", self.timeout)
+    }
+}
+
+impl std::error::Error for QueryTimedOut {}
+
+/// How long a query budget window lasts.
+pub const QUERY_BUDGET_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Returned when a dashboard has used up its upstream query budget for the current window.
+#[derive(Debug)]
+pub struct QueryBudgetExceeded {
+    pub limit: u32,
+    /// Seconds until the window resets.
+    pub retry_after: u64,
+}
+
+impl std::fmt::Display for QueryBudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The dashboard's budget of {} upstream queries per minute is used up. Retry after {} seconds",
+            self.limit, self.retry_after
+        )
+    }
+}
+
+impl std::error::Error for QueryBudgetExceeded {}
+
+/// Counts the upstream queries a dashboard makes in fixed one minute windows.
+#[derive(Debug, Default)]
+pub struct QueryBudget {
+    /// Start of the current window and the queries made in it.
+    window: Mutex<Option<(Instant, u32)>>,
+}
+
+impl QueryBudget {
+    /// Uses one query of the `limit` for the current window.
+    pub fn take(&self, limit: u32) -> Result<(), QueryBudgetExceeded> {
+        let mut window = self.window.lock().expect("Query budget lock poisoned");
+        let now = Instant::now();
+        let (start, used) = match *window {
+            Some((start, used)) if now.duration_since(start) < QUERY_BUDGET_WINDOW => (start, used),
+            _ => (now, 0),
+        };
+        if used >= limit {
+            let reset = QUERY_BUDGET_WINDOW.saturating_sub(now.duration_since(start));
+            return Err(QueryBudgetExceeded {
+                limit,
+                retry_after: reset.as_secs().max(1),
+            });
+        }
+        *window = Some((start, used + 1));
+        Ok(())
+    }
+}
+
+/// Runs an upstream query and gives up with QueryTimedOut after `timeout`.
+pub async fn with_timeout<T>(
+    timeout: std::time::Duration,
+    query: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::time::timeout(timeout, query)
+        .await
+        .map_err(|_| QueryTimedOut { timeout })?
+}
+
+/// Credentials sent with every query to a source. Each value is either set inline or read
+/// from the environment variable named by its `_env` counterpart.
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum SourceAuth {
+    /// Sent as `Authorization: Bearer <token>`.
+    #[serde(rename = "bearer")]
+    Bearer {
+        token: Option<String>,
+        token_env: Option<String>,
+    },
+    /// HTTP basic auth.
+    #[serde(rename = "basic")]
+    Basic {
+        username: Option<String>,
+        username_env: Option<String>,
+        password: Option<String>,
+        password_env: Option<String>,
+    },
+}
+
+// Hand written so tokens never end up in the logs.
+impl std::fmt::Debug for SourceAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceAuth::Bearer { token_env, .. } => f
+                .debug_struct("Bearer")
+                .field("token_env", token_env)
+                .finish_non_exhaustive(),
+            SourceAuth::Basic {
+                username,
+                username_env,
+                password_env,
+                ..
+            } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .field("username_env", username_env)
+                .field("password_env", password_env)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Returns the inline value if there is one and otherwise the value of the env variable.
+fn resolve_secret(value: &Option<String>, env: &Option<String>, name: &str) -> anyhow::Result<String> {
+    match (value, env) {
+        (Some(value), _) => Ok(value.clone()),
+        (None, Some(var)) => {
+            std::env::var(var).with_context(|| format!("Unable to read the {} from ${}", name, var))
+        }
+        (None, None) => Err(anyhow::anyhow!("Source auth needs a {} or {}_env", name, name)),
+    }
+}
+
+impl SourceAuth {
+    /// The Authorization header for the credentials. Env variables are read on every call so
+    /// rotated tokens get picked up. The header is marked sensitive so Debug output hides it.
+    pub fn header_value(&self) -> anyhow::Result<HeaderValue> {
+        let mut value = match self {
+            SourceAuth::Bearer { token, token_env } => HeaderValue::from_str(&format!(
+                "Bearer {}",
+                resolve_secret(token, token_env, "token")?
+            ))?,
+            SourceAuth::Basic {
+                username,
+                username_env,
+                password,
+                password_env,
+            } => {
+                let credentials = format!(
+                    "{}:{}",
+                    resolve_secret(username, username_env, "username")?,
+                    resolve_secret(password, password_env, "password")?
+                );
+                HeaderValue::from_str(&format!("Basic {}", BASE64_STANDARD.encode(credentials)))?
+            }
+        };
+        value.set_sensitive(true);
+        Ok(value)
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
 pub enum QueryType {
+    #[default]
     Range,
     Scalar,
 }
@@ -34,28 +254,533 @@ pub struct TimeSpan {
     pub step_seconds: i64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DataPoint {
     timestamp: f64,
     value: f64,
+    /// Color from the graph's thresholds. Only set for Scalar results.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    color: Option<String>,
+}
+
+impl DataPoint {
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+}
+
+/// One bucket of a native histogram sample. Which of the boundaries are inclusive is dropped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistogramBucket {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: f64,
+}
+
+/// A native histogram sample. Only the buckets that have observations are included.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistogramPoint {
+    pub timestamp: f64,
+    pub count: f64,
+    pub sum: f64,
+    pub buckets: Vec<HistogramBucket>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LogLine {
     timestamp: f64,
     line: String,
+    /// The timestamp formatted for display. Filled in by `LogQueryResult::format_times`.
+    #[serde(default)]
+    time_display: String,
+}
+
+/// Subsecond precision of the default RFC3339 log timestamps.
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+pub enum TimePrecision {
+    #[default]
+    #[serde(rename = "seconds")]
+    Seconds,
+    #[serde(rename = "millis")]
+    Millis,
+    #[serde(rename = "micros")]
+    Micros,
+    #[serde(rename = "nanos")]
+    Nanos,
+}
+
+impl From<TimePrecision> for SecondsFormat {
+    fn from(precision: TimePrecision) -> Self {
+        match precision {
+            TimePrecision::Seconds => SecondsFormat::Secs,
+            TimePrecision::Millis => SecondsFormat::Millis,
+            TimePrecision::Micros => SecondsFormat::Micros,
+            TimePrecision::Nanos => SecondsFormat::Nanos,
+        }
+    }
+}
+
+/// Formats a nanosecond timestamp with the strftime style `format` or as RFC3339 at `precision`
+/// if there is no format. Invalid formats fall back to RFC3339.
+fn format_log_time(nanos: f64, format: Option<&str>, precision: TimePrecision) -> String {
+    use std::fmt::Write;
+    let time = DateTime::from_timestamp_nanos(nanos as i64);
+    if let Some(format) = format {
+        let mut formatted = String::new();
+        if write!(formatted, "{}", time.format(format)).is_ok() {
+            return formatted;
+        }
+        error!(format, "Invalid log time_format. Falling back to RFC3339");
+    }
+    time.to_rfc3339_opts(precision.into(), true)
+}
+
+/// How the missing samples in a short gap get their values.
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+pub enum GapFillMethod {
+    /// Repeat the value from before the gap.
+    #[default]
+    #[serde(rename = "forward")]
+    Forward,
+    /// Draw a straight line across the gap.
+    #[serde(rename = "linear")]
+    Linear,
+}
+
+fn fill_gap(
+    filled: &mut Vec<DataPoint>,
+    next: &DataPoint,
+    step: f64,
+    max_steps: usize,
+    method: GapFillMethod,
+) {
+    let (start, start_value) = match filled.last() {
+        Some(prev) => (prev.timestamp, prev.value),
+        None => return,
+    };
+    let steps = ((next.timestamp - start) / step).round() as usize;
+    if steps < 2 || steps - 1 > max_steps {
+        return;
+    }
+    for i in 1..steps {
+        let value = match method {
+            GapFillMethod::Forward => start_value,
+            GapFillMethod::Linear => {
+                start_value + (next.value - start_value) * (i as f64 / steps as f64)
+            }
+        };
+        filled.push(DataPoint {
+            timestamp: start + step * i as f64,
+            value,
+            color: None,
+        });
+    }
+}
+
+/// A rough estimate of how expensive a plot's query is.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QueryEstimate {
+    pub series: usize,
+    pub points_per_series: i64,
+}
+
+/// One series reduced to summary values for table panels.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TableRow {
+    pub labels: HashMap<String, String>,
+    pub last: f64,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+}
+
+impl TableRow {
+    fn new(labels: &HashMap<String, String>, columns: &[String], points: &[DataPoint]) -> Option<Self> {
+        let last = points.last()?.value;
+        let mut min = f64::INFINITY;
+        let mut max = f64::NEG_INFINITY;
+        let mut sum = 0.0;
+        for point in points {
+            min = min.min(point.value);
+            max = max.max(point.value);
+            sum += point.value;
+        }
+        let labels = if columns.is_empty() {
+            labels.clone()
+        } else {
+            labels
+                .iter()
+                .filter(|(k, _)| columns.contains(k))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        };
+        Some(Self {
+            labels,
+            last,
+            min,
+            max,
+            avg: sum / points.len() as f64,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum MetricsQueryResult {
     Series(Vec<(HashMap<String, String>, PlotConfig, Vec<DataPoint>)>),
     Scalar(Vec<(HashMap<String, String>, PlotConfig, DataPoint)>),
+    /// Native histogram samples from a plot with `histogram: true`. Range queries have a point
+    /// per step and instant queries a single point.
+    Histogram(Vec<(HashMap<String, String>, PlotConfig, Vec<HistogramPoint>)>),
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum LogQueryResult {
     StreamInstant(Vec<(HashMap<String, String>, LogLine)>),
     Stream(Vec<(HashMap<String, String>, Vec<LogLine>)>),
+    /// Stream lines from every stream sorted newest first. Timestamps are nanoseconds.
+    MergedStream(Vec<(HashMap<String, String>, LogLine)>),
+}
+
+/// Replacement for the values of redacted labels. Masking rather than dropping the label keeps
+/// the label keys visible so it is obvious something was redacted.
+pub const REDACTED_VALUE: &str = "***";
+
+fn redact_label_set(labels: &mut HashMap<String, String>, redact: &[String]) {
+    for label in redact {
+        if let Some(value) = labels.get_mut(label) {
+            *value = REDACTED_VALUE.to_string();
+        }
+    }
+}
+
+/// Shortens label values longer than `max` characters and marks them with an ellipsis.
+fn truncate_label_set(labels: &mut HashMap<String, String>, max: usize) {
+    for value in labels.values_mut() {
+        if let Some((idx, _)) = value.char_indices().nth(max) {
+            value.truncate(idx);
+            value.push('…');
+        }
+    }
+}
+
+impl MetricsQueryResult {
+    /// Reduces every series to a single row. Only the labels in `columns` are kept unless it is
+    /// empty. Series without any points are dropped. Histograms have no single value so they
+    /// get no rows.
+    pub fn table_rows(&self, columns: &[String]) -> Vec<TableRow> {
+        match self {
+            MetricsQueryResult::Series(v) => v
+                .iter()
+                .filter_map(|(labels, _, points)| TableRow::new(labels, columns, points))
+                .collect(),
+            MetricsQueryResult::Scalar(v) => v
+                .iter()
+                .filter_map(|(labels, _, point)| {
+                    TableRow::new(labels, columns, std::slice::from_ref(point))
+                })
+                .collect(),
+            MetricsQueryResult::Histogram(_) => Vec::new(),
+        }
+    }
+
+    /// Drops the series whose points don't satisfy `keep`. Returns the number dropped.
+    /// Histograms are always kept.
+    pub fn retain_series<F: Fn(&[DataPoint]) -> bool>(&mut self, keep: F) -> usize {
+        let before = self.series_count();
+        match self {
+            MetricsQueryResult::Series(v) => v.retain(|(_, _, points)| keep(points)),
+            MetricsQueryResult::Scalar(v) => {
+                v.retain(|(_, _, point)| keep(std::slice::from_ref(point)))
+            }
+            MetricsQueryResult::Histogram(_) => {}
+        }
+        before - self.series_count()
+    }
+
+    /// Reduces every Series longer than `max_points` by splitting it into `max_points / 2`
+    /// buckets and keeping each bucket's lowest and highest point in time order. Unlike taking
+    /// every Nth point this keeps spikes visible. Buckets without a finite value keep their
+    /// first point so gaps still break the line. A `max_points` under 2 leaves room for one
+    /// bucket so only its highest point is kept. Scalar results are left alone.
+    pub fn downsample(&mut self, max_points: usize) {
+        let MetricsQueryResult::Series(v) = self else {
+            return;
+        };
+        let buckets = (max_points / 2).max(1);
+        for (_, _, points) in v.iter_mut() {
+            if points.len() <= max_points.max(1) {
+                continue;
+            }
+            let mut reduced = Vec::with_capacity(buckets * 2);
+            let bucket_len = points.len().div_ceil(buckets);
+            for bucket in points.chunks(bucket_len) {
+                let finite = bucket.iter().enumerate().filter(|(_, p)| p.value.is_finite());
+                let min = finite.clone().min_by(|(_, a), (_, b)| a.value.total_cmp(&b.value));
+                let max = finite.max_by(|(_, a), (_, b)| a.value.total_cmp(&b.value));
+                match (min, max) {
+                    (Some((min_idx, _)), Some((max_idx, _))) if min_idx == max_idx => {
+                        reduced.push(bucket[min_idx].clone());
+                    }
+                    (Some(_), Some((max_idx, _))) if max_points < 2 => {
+                        reduced.push(bucket[max_idx].clone());
+                    }
+                    (Some((min_idx, _)), Some((max_idx, _))) => {
+                        reduced.push(bucket[min_idx.min(max_idx)].clone());
+                        reduced.push(bucket[min_idx.max(max_idx)].clone());
+                    }
+                    _ => reduced.push(bucket[0].clone()),
+                }
+            }
+            *points = reduced;
+        }
+    }
+
+    /// Replaces each Series value with the running total up to that point. NaN and infinite
+    /// values add nothing so a gap in the data doesn't poison the rest of the total.
+    pub fn cumulative(&mut self) {
+        if let MetricsQueryResult::Series(v) = self {
+            for (_, _, points) in v.iter_mut() {
+                let mut total = 0.0;
+                for point in points.iter_mut() {
+                    if point.value.is_finite() {
+                        total += point.value;
+                    }
+                    point.value = total;
+                }
+            }
+        }
+    }
+
+    /// Fills gaps of up to `max_steps` missing samples in every Series. Longer gaps are left
+    /// as breaks in the line.
+    pub fn fill_gaps(&mut self, step_seconds: i64, max_steps: usize, method: GapFillMethod) {
+        if let MetricsQueryResult::Series(v) = self {
+            let step = step_seconds.max(1) as f64;
+            for (_, _, points) in v.iter_mut() {
+                let mut filled = Vec::with_capacity(points.len());
+                for point in points.drain(0..) {
+                    fill_gap(&mut filled, &point, step, max_steps, method);
+                    filled.push(point);
+                }
+                *points = filled;
+            }
+        }
+    }
+
+    /// True if the result has no data points.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            MetricsQueryResult::Series(v) => v.iter().all(|(_, _, points)| points.is_empty()),
+            MetricsQueryResult::Scalar(v) => v.is_empty(),
+            MetricsQueryResult::Histogram(v) => v.iter().all(|(_, _, points)| points.is_empty()),
+        }
+    }
+
+    /// Converts Scalar results into single point series so they plot on a time axis.
+    pub fn into_series(self) -> Self {
+        match self {
+            MetricsQueryResult::Scalar(v) => MetricsQueryResult::Series(
+                v.into_iter()
+                    .map(|(labels, meta, point)| (labels, meta, vec![point]))
+                    .collect(),
+            ),
+            series => series,
+        }
+    }
+
+    /// Number of series in the result.
+    pub fn series_count(&self) -> usize {
+        match self {
+            MetricsQueryResult::Series(v) => v.len(),
+            MetricsQueryResult::Scalar(v) => v.len(),
+            MetricsQueryResult::Histogram(v) => v.len(),
+        }
+    }
+
+    /// Replaces the legend name format of every series.
+    pub fn set_name_format(&mut self, name_format: &str) {
+        match self {
+            MetricsQueryResult::Series(v) => {
+                for (_, meta, _) in v.iter_mut() {
+                    meta.set_name_format(name_format);
+                }
+            }
+            MetricsQueryResult::Scalar(v) => {
+                for (_, meta, _) in v.iter_mut() {
+                    meta.set_name_format(name_format);
+                }
+            }
+            MetricsQueryResult::Histogram(v) => {
+                for (_, meta, _) in v.iter_mut() {
+                    meta.set_name_format(name_format);
+                }
+            }
+        }
+    }
+
+    /// Renders every series' `{label}` name_format into its legend name.
+    pub fn resolve_names(&mut self) {
+        match self {
+            MetricsQueryResult::Series(v) => {
+                for (labels, meta, _) in v.iter_mut() {
+                    meta.resolve_name(labels);
+                }
+            }
+            MetricsQueryResult::Scalar(v) => {
+                for (labels, meta, _) in v.iter_mut() {
+                    meta.resolve_name(labels);
+                }
+            }
+            MetricsQueryResult::Histogram(v) => {
+                for (labels, meta, _) in v.iter_mut() {
+                    meta.resolve_name(labels);
+                }
+            }
+        }
+    }
+
+    /// Truncates label values longer than `max` characters. Series are never merged so two
+    /// series whose labels only differ past the cutoff stay separate but look the same.
+    pub fn truncate_labels(&mut self, max: usize) {
+        match self {
+            MetricsQueryResult::Series(v) => {
+                for (labels, _, _) in v.iter_mut() {
+                    truncate_label_set(labels, max);
+                }
+            }
+            MetricsQueryResult::Scalar(v) => {
+                for (labels, _, _) in v.iter_mut() {
+                    truncate_label_set(labels, max);
+                }
+            }
+            MetricsQueryResult::Histogram(v) => {
+                for (labels, _, _) in v.iter_mut() {
+                    truncate_label_set(labels, max);
+                }
+            }
+        }
+    }
+
+    /// Masks the values of the listed labels in every series.
+    pub fn redact(&mut self, redact: &[String]) {
+        match self {
+            MetricsQueryResult::Series(v) => {
+                for (labels, _, _) in v.iter_mut() {
+                    redact_label_set(labels, redact);
+                }
+            }
+            MetricsQueryResult::Scalar(v) => {
+                for (labels, _, _) in v.iter_mut() {
+                    redact_label_set(labels, redact);
+                }
+            }
+            MetricsQueryResult::Histogram(v) => {
+                for (labels, _, _) in v.iter_mut() {
+                    redact_label_set(labels, redact);
+                }
+            }
+        }
+    }
+}
+
+impl MetricsQueryResult {
+    /// Sets the color of every Scalar value from the thresholds. Series are left alone.
+    pub fn apply_thresholds(&mut self, thresholds: &[Threshold]) {
+        if let MetricsQueryResult::Scalar(v) = self {
+            for (_, _, point) in v.iter_mut() {
+                point.color = threshold_color(thresholds, point.value).map(str::to_string);
+            }
+        }
+    }
+}
+
+impl LogQueryResult {
+    /// The total number of lines across every stream.
+    pub fn line_count(&self) -> usize {
+        match self {
+            LogQueryResult::StreamInstant(lines) | LogQueryResult::MergedStream(lines) => lines.len(),
+            LogQueryResult::Stream(streams) => streams.iter().map(|(_, lines)| lines.len()).sum(),
+        }
+    }
+
+    /// Merges Stream results into a MergedStream. Other results are returned as is.
+    pub fn merge_streams(self) -> Self {
+        match self {
+            LogQueryResult::Stream(streams) => {
+                let mut merged = Vec::new();
+                for (labels, lines) in streams {
+                    merged.extend(lines.into_iter().map(|line| (labels.clone(), line)));
+                }
+                merged.sort_by(|(_, a), (_, b)| b.timestamp.total_cmp(&a.timestamp));
+                LogQueryResult::MergedStream(merged)
+            }
+            result => result,
+        }
+    }
+
+    /// Fills in the display time of every line. See `format_log_time`.
+    pub fn format_times(&mut self, format: Option<&str>, precision: TimePrecision) {
+        match self {
+            // Instant results are timestamped in seconds.
+            LogQueryResult::StreamInstant(v) => {
+                for (_, line) in v.iter_mut() {
+                    line.time_display =
+                        format_log_time(line.timestamp * 1_000_000_000.0, format, precision);
+                }
+            }
+            // Stream results are timestamped in nanoseconds.
+            LogQueryResult::Stream(v) => {
+                for (_, lines) in v.iter_mut() {
+                    for line in lines.iter_mut() {
+                        line.time_display = format_log_time(line.timestamp, format, precision);
+                    }
+                }
+            }
+            LogQueryResult::MergedStream(v) => {
+                for (_, line) in v.iter_mut() {
+                    line.time_display = format_log_time(line.timestamp, format, precision);
+                }
+            }
+        }
+    }
+
+    /// Truncates label values longer than `max` characters. See
+    /// `MetricsQueryResult::truncate_labels`.
+    pub fn truncate_labels(&mut self, max: usize) {
+        match self {
+            LogQueryResult::StreamInstant(v) | LogQueryResult::MergedStream(v) => {
+                for (labels, _) in v.iter_mut() {
+                    truncate_label_set(labels, max);
+                }
+            }
+            LogQueryResult::Stream(v) => {
+                for (labels, _) in v.iter_mut() {
+                    truncate_label_set(labels, max);
+                }
+            }
+        }
+    }
+
+    /// Masks the values of the listed labels in every stream.
+    pub fn redact(&mut self, redact: &[String]) {
+        match self {
+            LogQueryResult::StreamInstant(v) => {
+                for (labels, _) in v.iter_mut() {
+                    redact_label_set(labels, redact);
+                }
+            }
+            LogQueryResult::Stream(v) => {
+                for (labels, _) in v.iter_mut() {
+                    redact_label_set(labels, redact);
+                }
+            }
+            LogQueryResult::MergedStream(v) => {
+                for (labels, _) in v.iter_mut() {
+                    redact_label_set(labels, redact);
+                }
+            }
+        }
+    }
 }
 
 impl std::fmt::Debug for MetricsQueryResult {
@@ -76,6 +801,9 @@ impl std::fmt::Debug for MetricsQueryResult {
             MetricsQueryResult::Scalar(v) => {
                 f.write_fmt(format_args!("{} traces", v.len()))?;
             }
+            MetricsQueryResult::Histogram(v) => {
+                f.write_fmt(format_args!("{} histogram traces", v.len()))?;
+            }
         }
         Ok(())
     }
@@ -87,6 +815,9 @@ impl std::fmt::Debug for LogQueryResult {
             LogQueryResult::StreamInstant(v) => {
                 f.write_fmt(format_args!("{} traces", v.len()))?;
             }
+            LogQueryResult::MergedStream(v) => {
+                f.write_fmt(format_args!("merged stream line count = {}", v.len()))?;
+            }
             LogQueryResult::Stream(v) => {
                 f.write_fmt(format_args!("stream trace count = {}", v.len()))?;
                 for (idx, (tags, trace)) in v.iter().enumerate() {
@@ -102,5 +833,189 @@ impl std::fmt::Debug for LogQueryResult {
         Ok(())
     }
 }
+pub use export::*;
 pub use loki::*;
+pub use metrics::*;
 pub use prom::*;
+
+#[cfg(test)]
+mod tests {
+    use super::test_server::points;
+    use super::*;
+
+    fn series(values: &[&[f64]]) -> MetricsQueryResult {
+        MetricsQueryResult::Series(
+            values
+                .iter()
+                .map(|values| (HashMap::new(), PlotConfig::default(), points(30, values)))
+                .collect(),
+        )
+    }
+
+    fn values_of(points: &[DataPoint]) -> Vec<f64> {
+        points.iter().map(DataPoint::value).collect()
+    }
+
+    /// The values of every Series in the result.
+    fn values(result: &MetricsQueryResult) -> Vec<Vec<f64>> {
+        match result {
+            MetricsQueryResult::Series(v) => v.iter().map(|(_, _, points)| values_of(points)).collect(),
+            _ => panic!("Expected a range result"),
+        }
+    }
+
+    #[test]
+    fn cumulative_is_a_running_total() {
+        let mut result = series(&[&[1.0, 2.0, 3.0, -1.0], &[5.0]]);
+        result.cumulative();
+        assert_eq!(values(&result), vec![vec![1.0, 3.0, 6.0, 5.0], vec![5.0]]);
+    }
+
+    #[test]
+    fn cumulative_skips_nan_and_infinite_values() {
+        let mut result = series(&[&[f64::NAN, 1.0, f64::NAN, 2.0, f64::INFINITY, 3.0]]);
+        result.cumulative();
+        assert_eq!(values(&result), vec![vec![0.0, 1.0, 1.0, 3.0, 3.0, 6.0]]);
+    }
+
+    /// A series from 0 to 8 with a one sample gap at 2 and a three sample gap from 5 to 7.
+    fn gappy_series() -> MetricsQueryResult {
+        let mut series = points(30, &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0]);
+        series.retain(|point| ![2.0, 5.0, 6.0, 7.0].contains(&point.value));
+        MetricsQueryResult::Series(vec![(HashMap::new(), PlotConfig::default(), series)])
+    }
+
+    #[test]
+    fn fill_gaps_fills_short_gaps_only() {
+        let mut result = gappy_series();
+        result.fill_gaps(30, 1, GapFillMethod::Forward);
+        assert_eq!(values(&result), vec![vec![0.0, 1.0, 1.0, 3.0, 4.0, 8.0]]);
+        let mut result = gappy_series();
+        result.fill_gaps(30, 1, GapFillMethod::Linear);
+        assert_eq!(values(&result), vec![vec![0.0, 1.0, 2.0, 3.0, 4.0, 8.0]]);
+        let MetricsQueryResult::Series(v) = &result else {
+            unreachable!()
+        };
+        let timestamps = v[0].2.iter().map(|p| p.timestamp).collect::<Vec<_>>();
+        let expected = [0, 1, 2, 3, 4, 8].map(|i| (test_server::START + 30 * i) as f64);
+        assert_eq!(timestamps, expected);
+    }
+
+    #[test]
+    fn fill_gaps_up_to_max_steps() {
+        let mut result = gappy_series();
+        result.fill_gaps(30, 3, GapFillMethod::Linear);
+        assert_eq!(values(&result), vec![(0..=8).map(f64::from).collect::<Vec<_>>()]);
+    }
+
+    fn stream(job: &str, lines: &[(f64, &str)]) -> (HashMap<String, String>, Vec<LogLine>) {
+        (
+            HashMap::from([("job".to_string(), job.to_string())]),
+            lines
+                .iter()
+                .map(|(timestamp, line)| LogLine {
+                    timestamp: *timestamp,
+                    line: line.to_string(),
+                    time_display: String::new(),
+                })
+                .collect(),
+        )
+    }
+
+    fn streams() -> LogQueryResult {
+        LogQueryResult::Stream(vec![
+            stream("api", &[(1.0, "api 1"), (3.0, "api 3")]),
+            stream("db", &[(2.0, "db 2"), (4.0, "db 4")]),
+        ])
+    }
+
+    #[test]
+    fn merge_streams_sorts_lines_newest_first_tagged_by_stream() {
+        let merged = streams().merge_streams();
+        assert_eq!(merged.line_count(), 4);
+        let LogQueryResult::MergedStream(lines) = merged else {
+            panic!("Expected merged lines");
+        };
+        let lines = lines
+            .iter()
+            .map(|(labels, line)| (labels["job"].as_str(), line.line.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![("db", "db 4"), ("api", "api 3"), ("db", "db 2"), ("api", "api 1")]
+        );
+    }
+
+    #[test]
+    fn truncate_labels_shortens_long_values_on_char_boundaries() {
+        let labels = HashMap::from([
+            ("short".to_string(), "abc".to_string()),
+            ("exact".to_string(), "abcd".to_string()),
+            ("long".to_string(), "abcdef".to_string()),
+            ("wide".to_string(), "ééééé".to_string()),
+        ]);
+        let mut result = MetricsQueryResult::Scalar(vec![(
+            labels.clone(),
+            PlotConfig::default(),
+            points(30, &[1.0]).remove(0),
+        )]);
+        result.truncate_labels(4);
+        let MetricsQueryResult::Scalar(v) = &result else {
+            unreachable!()
+        };
+        assert_eq!(v[0].0["short"], "abc");
+        assert_eq!(v[0].0["exact"], "abcd");
+        assert_eq!(v[0].0["long"], "abcd…");
+        assert_eq!(v[0].0["wide"], "éééé…");
+        let mut lines = LogQueryResult::Stream(vec![(labels, Vec::new())]);
+        lines.truncate_labels(4);
+        let LogQueryResult::Stream(streams) = &lines else {
+            unreachable!()
+        };
+        assert_eq!(streams[0].0["long"], "abcd…");
+    }
+
+    #[test]
+    fn downsample_keeps_spikes_in_time_order() {
+        let mut values = vec![1.0; 100];
+        values[57] = 500.0;
+        values[13] = -20.0;
+        let mut result = series(&[&values, &[1.0, 2.0, 3.0]]);
+        result.downsample(10);
+        let MetricsQueryResult::Series(v) = &result else {
+            unreachable!()
+        };
+        let reduced = &v[0].2;
+        assert!(reduced.len() <= 10, "{} points", reduced.len());
+        assert!(reduced.iter().any(|p| p.value == 500.0));
+        assert!(reduced.iter().any(|p| p.value == -20.0));
+        assert!(reduced.windows(2).all(|w| w[0].timestamp < w[1].timestamp));
+        // Series under the limit are left alone.
+        assert_eq!(values_of(&v[1].2), vec![1.0, 2.0, 3.0]);
+        // Too few points for a low and a high keeps just the highest.
+        for max_points in [0, 1] {
+            let mut result = series(&[&values]);
+            result.downsample(max_points);
+            let MetricsQueryResult::Series(v) = &result else {
+                unreachable!()
+            };
+            assert_eq!(values_of(&v[0].2), vec![500.0], "max_points {}", max_points);
+        }
+    }
+
+    #[test]
+    fn downsample_keeps_gaps_as_breaks() {
+        let nan = f64::NAN;
+        let mut result = series(&[&[1.0, 2.0, nan, nan, nan, nan, 3.0, 4.0]]);
+        result.downsample(6);
+        let MetricsQueryResult::Series(v) = &result else {
+            unreachable!()
+        };
+        // Three buckets of up to three points. The all NaN one keeps its first point.
+        let reduced = values_of(&v[0].2);
+        assert_eq!(reduced.len(), 5);
+        assert_eq!(reduced[..2], [1.0, 2.0]);
+        assert!(reduced[2].is_nan());
+        assert_eq!(reduced[3..], [3.0, 4.0]);
+    }
+}
@@ -12,21 +12,460 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 use chrono::prelude::*;
-use prometheus_http_query::{
-    response::{Data, PromqlResult},
-    Client,
-};
-use tracing::debug;
+use prometheus_http_query::response::{Data, PromqlResult};
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use serde::Deserialize;
+use tracing::{debug, error, info};
 
 use crate::dashboard::PlotConfig;
 
-use super::{DataPoint, MetricsQueryResult, QueryType, TimeSpan};
+use super::{
+    http_client, observe_query, with_timeout, DataPoint, HistogramBucket, HistogramPoint,
+    MetricsQueryResult, QueryBudget, QueryBudgetExceeded, QueryEstimate, QueryType, SourceAuth,
+    TimeSpan, DEFAULT_QUERY_TIMEOUT_SECONDS, ORG_ID_HEADER, ORIGIN_HEADER,
+};
 
 pub const FILTER_PLACEHOLDER: &'static str = "FILTERS";
 pub const FILTER_COMMA_PLACEHOLDER: &'static str = ",FILTERS";
 pub const FILTER_PLACEHOLDER_COMMA: &'static str = "FILTERS,";
+/// Replaced with the query step as a duration like `30s` after the filters are substituted.
+pub const INTERVAL_PLACEHOLDER: &str = "$__interval";
+/// Prometheus rejects range queries that would return more points than this per series.
+pub const MAX_POINTS_PER_SERIES: i64 = 11_000;
+/// Range queries get a larger step when they would return more points per series than this.
+/// Browsers struggle to draw much more.
+pub const DEFAULT_MAX_POINTS: i64 = 1_000;
+/// Step used when the query has no span.
+const DEFAULT_STEP_SECONDS: i64 = 30;
+/// Rendered queries longer than this are sent as a POST form instead of in the GET url. Long
+/// filtered queries can otherwise get rejected with 414 URI Too Long.
+pub const DEFAULT_POST_QUERY_LENGTH: usize = 2048;
+
+static POST_QUERY_LENGTH: OnceLock<usize> = OnceLock::new();
+static MAX_POINTS: OnceLock<i64> = OnceLock::new();
+static CACHE_MAX_ENTRIES: OnceLock<usize> = OnceLock::new();
+
+/// Most results the result cache holds before the least recently used ones are evicted.
+pub const DEFAULT_CACHE_MAX_ENTRIES: usize = 1000;
+/// Instant queries against now are never cached longer than this since they show the current
+/// value.
+pub const MAX_INSTANT_CACHE_SECONDS: u64 = 5;
+
+/// Sets the result cache size. Must be called before the first query.
+pub fn set_cache_max_entries(entries: usize) {
+    if CACHE_MAX_ENTRIES.set(entries.max(1)).is_err() {
+        error!(entries, "Cache max entries was already set");
+    }
+}
+
+/// Sets the points per series that range query steps get raised to stay under. It is capped
+/// at MAX_POINTS_PER_SERIES. Must be called before the first query.
+pub fn set_max_points(points: i64) {
+    if MAX_POINTS.set(points.clamp(1, MAX_POINTS_PER_SERIES)).is_err() {
+        error!(points, "Max points was already set");
+    }
+}
+
+fn max_points() -> i64 {
+    *MAX_POINTS.get().unwrap_or(&DEFAULT_MAX_POINTS)
+}
+
+/// Sets the query length past which queries are POSTed. Must be called before the first query.
+pub fn set_post_query_length(length: usize) {
+    if POST_QUERY_LENGTH.set(length).is_err() {
+        error!(length, "Post query length was already set");
+    }
+}
+
+/// Escapes a value for use inside a double quoted PromQL string so it can't end the string
+/// early and inject its own matchers. Newlines are escaped too since PromQL strings can't span
+/// lines.
+pub fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// True if the name is a valid prometheus label name.
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Renders the matcher for a filter key and value. The key is the label with an optional
+/// prefix picking the match operator. `job` is a regex match `job=~"..."`, `exact-job` an
+/// exact match `job="..."` and `neq-job` a negative match `job!="..."`. The prefixes can't be
+/// mistaken for a label since `-` isn't valid in label names. None if the label is invalid.
+fn filter_matcher(key: &str, value: &str) -> Option<String> {
+    let (label, op) = if let Some(label) = key.strip_prefix("exact-") {
+        (label, "=")
+    } else if let Some(label) = key.strip_prefix("neq-") {
+        (label, "!=")
+    } else {
+        (key, "=~")
+    };
+    is_label_name(label).then(|| format!("{}{}\"{}\"", label, op, escape_label_value(value)))
+}
+
+/// Keywords that can appear bare in a PromQL query without being a metric selector.
+const PROMQL_KEYWORDS: &[&str] = &[
+    "and", "or", "unless", "bool", "offset", "by", "without", "on", "ignoring", "group_left",
+    "group_right", "inf", "nan",
+];
+/// Keywords followed by a list of label names rather than an expression.
+const LABEL_LIST_KEYWORDS: &[&str] =
+    &["by", "without", "on", "ignoring", "group_left", "group_right"];
+
+/// True if every series selector in the query has a FILTERS placeholder in its matchers.
+/// Queries like `a{FILTERS} / b` have a selector that filters can't be added to. Strings,
+/// ranges and label lists like `by (job)` are skipped. Anything followed by `(` or `by` is
+/// taken to be a function or aggregation.
+pub fn every_selector_filtered(query: &str) -> bool {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == ':';
+    let chars: Vec<char> = query.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '"' || c == '\'' || c == '`' {
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                i += if chars[i] == '\\' && c != '`' { 2 } else { 1 };
+            }
+            i += 1;
+        } else if c == '#' {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '[' {
+            while i < chars.len() && chars[i] != ']' {
+                i += 1;
+            }
+            i += 1;
+        } else if c == '{' {
+            let start = i;
+            while i < chars.len() && chars[i] != '}' {
+                i += 1;
+            }
+            let matchers: String = chars[start..i].iter().collect();
+            if !matchers.contains(FILTER_PLACEHOLDER) {
+                return false;
+            }
+            i += 1;
+        } else if c.is_ascii_alphabetic() || c == '_' || c == ':' {
+            let start = i;
+            while i < chars.len() && is_name_char(chars[i]) {
+                i += 1;
+            }
+            let word = chars[start..i].iter().collect::<String>().to_lowercase();
+            let mut next = i;
+            while next < chars.len() && chars[next].is_whitespace() {
+                next += 1;
+            }
+            let next_word: String =
+                chars[next..].iter().take_while(|c| is_name_char(**c)).collect();
+            match chars.get(next) {
+                // Aggregations can have their label list first like `sum by (job) (...)`.
+                Some(_) if matches!(next_word.to_lowercase().as_str(), "by" | "without") => {}
+                // The matchers are checked on the next pass.
+                Some('{') => {}
+                Some('(') if LABEL_LIST_KEYWORDS.contains(&word.as_str()) => {
+                    while i < chars.len() && chars[i] != ')' {
+                        i += 1;
+                    }
+                }
+                Some('(') => {}
+                _ if PROMQL_KEYWORDS.contains(&word.as_str()) => {}
+                _ => return false,
+            }
+        } else if c.is_ascii_digit() || c == '.' || c == '$' {
+            // Numbers, durations like 5m and $__interval.
+            i += 1;
+            while i < chars.len() && (is_name_char(chars[i]) || chars[i] == '.') {
+                i += 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    true
+}
+
+fn use_post(query: &str) -> bool {
+    query.len() > *POST_QUERY_LENGTH.get().unwrap_or(&DEFAULT_POST_QUERY_LENGTH)
+}
+
+/// A rendered upstream query. Also identifies the query's result in the result cache.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    source: String,
+    query: String,
+    instant: bool,
+    start: i64,
+    end: i64,
+    step_seconds: i64,
+    thanos: bool,
+    dedup: bool,
+    partial_response: bool,
+    /// Results are only shared between queries with the same credentials and tenant.
+    auth: Option<HeaderValue>,
+    org_id: Option<String>,
+}
+
+impl CacheKey {
+    /// The query_type the query metrics record the query under.
+    fn query_type(&self) -> QueryType {
+        if self.instant {
+            QueryType::Scalar
+        } else {
+            QueryType::Range
+        }
+    }
+
+    /// Builds the upstream request with the origin, auth, and tenant headers and any thanos
+    /// params. Long queries are sent as a POST form. See `use_post`.
+    fn build_request(&self, origin: &str) -> anyhow::Result<reqwest::RequestBuilder> {
+        // Any base path in the source url is kept. A trailing slash would double up with the
+        // api path.
+        let source = self.source.trim_end_matches('/');
+        let (url, mut params) = if self.instant {
+            // Evaluated at the end the key was rendered with so a cached value is for the time
+            // it is keyed on rather than whenever it happened to be fetched.
+            (
+                format!("{}/api/v1/query", source),
+                vec![("query", self.query.clone()), ("time", self.end.to_string())],
+            )
+        } else {
+            (
+                format!("{}/api/v1/query_range", source),
+                vec![
+                    ("query", self.query.clone()),
+                    ("start", self.start.to_string()),
+                    ("end", self.end.to_string()),
+                    ("step", self.step_seconds.to_string()),
+                ],
+            )
+        };
+        if self.thanos {
+            params.push(("dedup", self.dedup.to_string()));
+            params.push(("partial_response", self.partial_response.to_string()));
+        }
+        let client = http_client()?;
+        let mut builder = if use_post(&self.query) {
+            client.post(url).form(&params)
+        } else {
+            client.get(url).query(&params)
+        };
+        builder = builder.header(ORIGIN_HEADER, HeaderValue::from_str(origin)?);
+        if let Some(ref auth) = self.auth {
+            builder = builder.header(AUTHORIZATION, auth.clone());
+        }
+        if let Some(ref org_id) = self.org_id {
+            builder = builder.header(ORG_ID_HEADER, HeaderValue::from_str(org_id)?);
+        }
+        Ok(builder)
+    }
+
+    /// Sends the query upstream.
+    async fn fetch(&self, origin: &str) -> anyhow::Result<PromqlResult> {
+        parse_response(self.fetch_raw(origin).await?).await
+    }
+
+    /// Sends the query upstream and returns the response unparsed. Used for native histograms
+    /// which the prometheus client can't decode.
+    async fn fetch_raw(&self, origin: &str) -> anyhow::Result<reqwest::Response> {
+        Ok(self.build_request(origin)?.send().await?)
+    }
+}
+
+/// The envelope every prometheus api response comes in.
+#[derive(Deserialize)]
+#[serde(tag = "status")]
+enum ApiResponse<D> {
+    #[serde(rename = "success")]
+    Success { data: D },
+    #[serde(rename = "error")]
+    Error {
+        #[serde(rename = "errorType")]
+        error_type: String,
+        error: String,
+    },
+}
+
+/// Unwraps the data of a prometheus api response. Error responses become an error with the
+/// message prometheus sent.
+async fn parse_response<D: serde::de::DeserializeOwned>(
+    response: reqwest::Response,
+) -> anyhow::Result<D> {
+    let status = response.status();
+    let response = response.json::<ApiResponse<D>>().await.map_err(|err| {
+        anyhow::anyhow!("Failed to parse the response from the source ({}): {}", status, err)
+    })?;
+    match response {
+        ApiResponse::Success { data } => Ok(data),
+        ApiResponse::Error { error_type, error } => {
+            Err(anyhow::anyhow!("Prometheus {} error: {}", error_type, error))
+        }
+    }
+}
+
+/// A native histogram sample as the prometheus api encodes it. Numbers are sent as strings.
+#[derive(Deserialize)]
+struct RawHistogram {
+    count: String,
+    sum: String,
+    /// `[boundary_rule, lower, upper, count]` for each bucket with observations.
+    #[serde(default)]
+    buckets: Vec<(u8, String, String, String)>,
+}
+
+impl RawHistogram {
+    fn into_point(self, timestamp: f64) -> anyhow::Result<HistogramPoint> {
+        Ok(HistogramPoint {
+            timestamp,
+            count: self.count.parse()?,
+            sum: self.sum.parse()?,
+            buckets: self
+                .buckets
+                .into_iter()
+                .map(|(_, lower, upper, count)| {
+                    Ok(HistogramBucket {
+                        lower: lower.parse()?,
+                        upper: upper.parse()?,
+                        count: count.parse()?,
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        })
+    }
+}
+
+/// A series of a matrix or vector result. Range queries fill in `histograms` and instant
+/// queries `histogram`. Float samples in the same result are ignored.
+#[derive(Deserialize)]
+struct RawHistogramSeries {
+    #[serde(default)]
+    metric: HashMap<String, String>,
+    #[serde(default)]
+    histograms: Vec<(f64, RawHistogram)>,
+    histogram: Option<(f64, RawHistogram)>,
+}
+
+#[derive(Deserialize)]
+struct RawHistogramData {
+    result: Vec<RawHistogramSeries>,
+}
+
+struct CacheEntry {
+    /// The result is served as is until then.
+    fresh_until: Instant,
+    /// After fresh_until the result is still served until then while it gets refreshed.
+    stale_until: Instant,
+    /// A background refresh is running.
+    refreshing: bool,
+    /// For evicting the least recently used entry once the cache is full.
+    last_used: Instant,
+    results: PromqlResult,
+}
+
+static RESULT_CACHE: OnceLock<Mutex<HashMap<CacheKey, CacheEntry>>> = OnceLock::new();
+
+fn result_cache() -> &'static Mutex<HashMap<CacheKey, CacheEntry>> {
+    RESULT_CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Returns the cached result for the key if it is fresh or within its stale window. The bool
+/// is true when the result is stale and the caller should refresh it. Only one caller gets
+/// told to refresh at a time. Expired entries are evicted here rather than by a background
+/// task.
+fn cached_result(key: &CacheKey) -> Option<(PromqlResult, bool)> {
+    let mut cache = result_cache().lock().expect("Result cache lock poisoned");
+    let now = Instant::now();
+    cache.retain(|_, entry| entry.stale_until > now);
+    let entry = cache.get_mut(key)?;
+    let refresh = entry.fresh_until <= now && !entry.refreshing;
+    entry.refreshing |= refresh;
+    entry.last_used = now;
+    Some((entry.results.clone(), refresh))
+}
+
+/// Drops the cached results of every source `matches` accepts. Sources are compared without a
+/// trailing slash. Returns the number of results evicted.
+pub fn invalidate_cached_results(matches: impl Fn(&str) -> bool) -> usize {
+    let mut cache = result_cache().lock().expect("Result cache lock poisoned");
+    let before = cache.len();
+    cache.retain(|key, _| !matches(key.source.trim_end_matches('/')));
+    before - cache.len()
+}
+
+fn cache_result(key: CacheKey, ttl: Duration, stale: Duration, results: &PromqlResult) {
+    let mut cache = result_cache().lock().expect("Result cache lock poisoned");
+    let max_entries = *CACHE_MAX_ENTRIES.get().unwrap_or(&DEFAULT_CACHE_MAX_ENTRIES);
+    if !cache.contains_key(&key) && cache.len() >= max_entries {
+        let oldest = cache
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            debug!(max_entries, "Evicting the least recently used promql result");
+            cache.remove(&oldest);
+        }
+    }
+    let now = Instant::now();
+    let fresh_until = now + ttl;
+    cache.insert(
+        key,
+        CacheEntry {
+            fresh_until,
+            stale_until: fresh_until + stale,
+            refreshing: false,
+            last_used: now,
+            results: results.clone(),
+        },
+    );
+}
+
+/// Refreshes a stale entry in a detached task. If the refresh fails the last good result keeps
+/// being served until its stale window ends and the next request retries the refresh.
+fn spawn_refresh(key: CacheKey, origin: String, timeout: Duration, ttl: Duration, stale: Duration) {
+    tokio::spawn(async move {
+        debug!(origin, "Refreshing stale promql results");
+        let refresh = with_timeout(timeout, key.fetch(&origin));
+        match observe_query(&key.source, &key.query_type(), refresh).await {
+            Ok(results) => cache_result(key, ttl, stale, &results),
+            Err(err) => {
+                error!(origin, ?err, "Refreshing stale promql results failed");
+                end_refresh(&key);
+            }
+        }
+    });
+}
+
+/// Lets the next request for a stale entry refresh it.
+fn end_refresh(key: &CacheKey) {
+    let mut cache = result_cache().lock().expect("Result cache lock poisoned");
+    if let Some(entry) = cache.get_mut(key) {
+        entry.refreshing = false;
+    }
+}
+
+/// The flavor of Prometheus compatible api a source speaks.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub enum SourceType {
+    #[default]
+    #[serde(rename = "prometheus")]
+    Prometheus,
+    /// Thanos query and query-frontend. Also used for other long term storage backends that
+    /// understand the `dedup` and `partial_response` params.
+    #[serde(rename = "thanos")]
+    Thanos,
+}
 
 #[derive(Debug)]
 pub struct PromQueryConn<'conn> {
@@ -35,6 +474,19 @@ pub struct PromQueryConn<'conn> {
     span: Option<TimeSpan>,
     query_type: QueryType,
     filters: Option<&'conn HashMap<&'conn str, &'conn str>>,
+    scope: &'conn [String],
+    strict_step: bool,
+    source_type: SourceType,
+    dedup: bool,
+    partial_response: bool,
+    origin: &'conn str,
+    auth: Option<&'conn SourceAuth>,
+    org_id: Option<&'conn str>,
+    timeout: Duration,
+    cache_ttl: Option<Duration>,
+    cache_stale: Duration,
+    budget: Option<(&'conn QueryBudget, u32)>,
+    live_end: bool,
     pub meta: PlotConfig,
 }
 
@@ -52,14 +504,119 @@ impl<'conn> PromQueryConn<'conn> {
             meta,
             span: None,
             filters: None,
+            scope: &[],
+            strict_step: false,
+            source_type: SourceType::Prometheus,
+            dedup: true,
+            partial_response: false,
+            origin: "",
+            auth: None,
+            org_id: None,
+            timeout: Duration::from_secs(DEFAULT_QUERY_TIMEOUT_SECONDS),
+            cache_ttl: None,
+            cache_stale: Duration::ZERO,
+            budget: None,
+            live_end: false,
         }
     }
 
+    /// Marks the span end as relative to now. e.g. `now` or `now-1h`. Cached queries align a
+    /// live end to the cache ttl so requests made moments apart share a result.
+    pub fn with_live_end(mut self, live_end: bool) -> Self {
+        self.live_end = live_end;
+        self
+    }
+
+    /// Counts every upstream query against the budget's limit. Cached results don't count and
+    /// are still served once the budget is used up.
+    pub fn with_budget(mut self, budget: Option<(&'conn QueryBudget, u32)>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Uses one query of the budget if there is one.
+    fn take_budget(&self) -> Result<(), QueryBudgetExceeded> {
+        match self.budget {
+            Some((budget, limit)) => budget.take(limit),
+            None => Ok(()),
+        }
+    }
+
+    /// Tags the query with where it originated for audit logging.
+    pub fn with_origin(mut self, origin: &'conn str) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// Sends the credentials with every query.
+    pub fn with_auth(mut self, auth: Option<&'conn SourceAuth>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Sets the X-Scope-OrgID tenant header on every query.
+    pub fn with_org_id(mut self, org_id: Option<&'conn str>) -> Self {
+        self.org_id = org_id;
+        self
+    }
+
+    /// Sets the source type. `dedup` and `partial_response` only apply to Thanos sources.
+    pub fn with_source_type(
+        mut self,
+        source_type: SourceType,
+        dedup: Option<bool>,
+        partial_response: Option<bool>,
+    ) -> Self {
+        self.source_type = source_type;
+        self.dedup = dedup.unwrap_or(true);
+        self.partial_response = partial_response.unwrap_or(false);
+        self
+    }
+
+    /// How long each upstream request gets before failing with QueryTimedOut.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Caches the results of get_results for this many seconds. None or 0 disables caching.
+    pub fn with_cache_seconds(mut self, cache_seconds: Option<u64>) -> Self {
+        self.cache_ttl = cache_seconds
+            .filter(|seconds| *seconds > 0)
+            .map(Duration::from_secs);
+        self
+    }
+
+    /// After the cache ttl a result is served for up to this many more seconds while it gets
+    /// refreshed in the background. Only applies when caching is enabled.
+    pub fn with_stale_seconds(mut self, stale_seconds: Option<u64>) -> Self {
+        self.cache_stale = Duration::from_secs(stale_seconds.unwrap_or(0));
+        self
+    }
+
+    pub fn with_query_type(mut self, query_type: QueryType) -> Self {
+        self.query_type = query_type;
+        self
+    }
+
+    pub fn query_type(&self) -> &QueryType {
+        &self.query_type
+    }
+
+    /// Filters substituted for the FILTERS placeholder. Keys are labels with an optional
+    /// operator prefix. See `filter_matcher`.
     pub fn with_filters(mut self, filters: &'conn HashMap<&'conn str, &'conn str>) -> Self {
         self.filters = Some(filters);
         self
     }
 
+    /// Matchers that get added to the filters of every query. Unlike filters they come from the
+    /// server rather than the client.
+    pub fn with_scope(mut self, scope: &'conn [String]) -> Self {
+        self.scope = scope;
+        self
+    }
+
     pub fn with_span(
         mut self,
         end: DateTime<Utc>,
@@ -74,22 +631,56 @@ impl<'conn> PromQueryConn<'conn> {
         self
     }
 
+    /// Keep the configured step instead of raising it to stay under the max points. Range
+    /// queries over MAX_POINTS_PER_SERIES fail.
+    pub fn with_strict_step(mut self, strict_step: bool) -> Self {
+        self.strict_step = strict_step;
+        self
+    }
+
+    /// The smallest step that keeps the span under `points` per series.
+    fn min_step_seconds(&self, points: i64) -> i64 {
+        self.span
+            .as_ref()
+            .map(|span| (span.duration.num_seconds() + points - 1) / points)
+            .unwrap_or(1)
+            .max(1)
+    }
+
+    /// The step of the range query in seconds. Steps too small for the span are raised to the
+    /// smallest one under the max points, DEFAULT_MAX_POINTS unless set, unless the step is
+    /// strict.
+    pub fn step_seconds(&self) -> i64 {
+        let step_seconds = self
+            .span
+            .as_ref()
+            .map(|span| span.step_seconds)
+            .unwrap_or(DEFAULT_STEP_SECONDS);
+        if self.strict_step {
+            step_seconds
+        } else {
+            step_seconds.max(self.min_step_seconds(max_points()))
+        }
+    }
+
     fn get_query(&self) -> String {
-        let first = true;
-        let mut filter_string = String::new();
         debug!(filters=?self.filters, orig=?self.query, "Filters from request");
+        // Scope matchers always come first. Client filters can only narrow them further since
+        // every matcher has to match.
+        let mut matchers = self.scope.to_vec();
         if let Some(filters) = self.filters {
-            for (k, v) in filters.iter() {
-                if !first {
-                    filter_string.push_str(",");
+            // Sort the labels so the same filter set always renders the same query. This keeps
+            // the rendered query usable as a cache key regardless of query param order.
+            let mut filters = filters.iter().collect::<Vec<(&&str, &&str)>>();
+            filters.sort();
+            for (k, v) in filters {
+                match filter_matcher(k, v) {
+                    Some(matcher) => matchers.push(matcher),
+                    None => debug!(label = k, "Skipping filter with an invalid label name"),
                 }
-                filter_string.push_str(*k);
-                filter_string.push_str("=~");
-                filter_string.push('"');
-                filter_string.push_str(*v);
-                filter_string.push('"');
             }
         }
+        let mut filter_string = matchers.join(",");
         let mut query = self.query.to_string();
         if self.query.contains(FILTER_PLACEHOLDER_COMMA) {
             debug!("Replacing Filter comma placeholder");
@@ -111,56 +702,295 @@ impl<'conn> PromQueryConn<'conn> {
             debug!("Replacing Filter placeholder");
             query = query.replace(FILTER_PLACEHOLDER, &filter_string)
         }
+        if query.contains(INTERVAL_PLACEHOLDER) {
+            let step_seconds = self.step_seconds();
+            debug!(step_seconds, "Replacing interval placeholder");
+            query = query.replace(INTERVAL_PLACEHOLDER, &format!("{}s", step_seconds.max(1)));
+        }
         query
     }
 
-    pub async fn get_results(&self) -> anyhow::Result<PromqlResult> {
-        debug!("Getting results for query");
-        let client = Client::try_from(self.source)?;
+    /// The cache ttl for this query. Instant queries against now only get a short one.
+    fn cache_ttl(&self) -> Option<Duration> {
+        let ttl = self.cache_ttl?;
+        match self.query_type {
+            QueryType::Scalar if self.live_end || self.span.is_none() => {
+                Some(ttl.min(Duration::from_secs(MAX_INSTANT_CACHE_SECONDS)))
+            }
+            _ => Some(ttl),
+        }
+    }
+
+    /// How long past the ttl a cached result is still served while it refreshes. Instant
+    /// queries against now are never served stale since they show the current value.
+    fn cache_stale(&self) -> Duration {
+        match self.query_type {
+            QueryType::Scalar if self.live_end || self.span.is_none() => Duration::ZERO,
+            _ => self.cache_stale,
+        }
+    }
+
+    /// Rounds a live end down to the cache ttl so every request in the same ttl window renders
+    /// the same cache key. Fixed ends and uncached queries are left alone.
+    fn cache_end(&self, end: i64) -> i64 {
+        match self.cache_ttl() {
+            Some(ttl) if self.live_end || self.span.is_none() => {
+                let ttl = (ttl.as_secs() as i64).max(1);
+                end - end.rem_euclid(ttl)
+            }
+            _ => end,
+        }
+    }
+
+    /// Works out the span, step and headers the query gets sent with.
+    fn cache_key(&self) -> anyhow::Result<CacheKey> {
         let (start, end, step_resolution) = if let Some(TimeSpan {
             end,
             duration: du,
-            step_seconds,
+            step_seconds: configured_step,
         }) = self.span
         {
             let start = end - du;
+            let step_seconds = self.step_seconds();
+            if let QueryType::Range = self.query_type {
+                if step_seconds < self.min_step_seconds(MAX_POINTS_PER_SERIES) {
+                    return Err(anyhow::anyhow!(
+                        "A {}s step over a {}s span returns more than {} points per series",
+                        step_seconds,
+                        du.num_seconds(),
+                        MAX_POINTS_PER_SERIES
+                    ));
+                }
+                if step_seconds != configured_step {
+                    info!(
+                        origin = self.origin,
+                        configured_step,
+                        step_seconds,
+                        max_points = max_points(),
+                        "Raised the query step to stay under the max points per series"
+                    );
+                }
+            }
             debug!(
                 ?start,
                 ?end,
                 step_seconds,
                 "Running Query with range values"
             );
-            (start.timestamp(), end.timestamp(), step_seconds as f64)
+            let end = self.cache_end(end.timestamp());
+            (end - du.num_seconds(), end, step_seconds)
         } else {
             let end = Utc::now();
             let start = end - chrono::Duration::minutes(10);
             debug!(
                 ?start,
                 ?end,
-                step_seconds = 30,
+                step_seconds = DEFAULT_STEP_SECONDS,
                 "Running Query with range values"
             );
-            (start.timestamp(), end.timestamp(), 30 as f64)
+            let end = self.cache_end(end.timestamp());
+            (end - 600, end, DEFAULT_STEP_SECONDS)
         };
         //debug!(start, end, step_resolution, "Running Query with range values");
         let query = self.get_query();
         debug!(?query, "Using promql query");
-        match self.query_type {
-            QueryType::Range => {
-                let results = client
-                    .query_range(&query, start, end, step_resolution)
-                    .get()
-                    .await?;
-                //debug!(?results, "range results");
-                Ok(results)
+        Ok(CacheKey {
+            source: self.source.to_string(),
+            query,
+            instant: matches!(self.query_type, QueryType::Scalar),
+            start,
+            end,
+            step_seconds: step_resolution,
+            thanos: matches!(self.source_type, SourceType::Thanos),
+            dedup: self.dedup,
+            partial_response: self.partial_response,
+            auth: self.auth.map(SourceAuth::header_value).transpose()?,
+            org_id: self.org_id.map(str::to_string),
+        })
+    }
+
+    /// The key for an instant query of `query` at `time` with this conn's source settings.
+    fn instant_key(&self, query: String, time: i64) -> anyhow::Result<CacheKey> {
+        Ok(CacheKey {
+            source: self.source.to_string(),
+            query,
+            instant: true,
+            start: time,
+            end: time,
+            step_seconds: 0,
+            thanos: matches!(self.source_type, SourceType::Thanos),
+            dedup: self.dedup,
+            partial_response: self.partial_response,
+            auth: self.auth.map(SourceAuth::header_value).transpose()?,
+            org_id: self.org_id.map(str::to_string),
+        })
+    }
+
+    /// Sends the query upstream bypassing the cache. The query counts against the budget and
+    /// gets recorded in the query metrics.
+    async fn fetch(&self, key: &CacheKey) -> anyhow::Result<PromqlResult> {
+        self.take_budget()?;
+        observe_query(
+            self.source,
+            &key.query_type(),
+            with_timeout(self.timeout, key.fetch(self.origin)),
+        )
+        .await
+    }
+
+    pub async fn get_results(&self) -> anyhow::Result<PromqlResult> {
+        debug!("Getting results for query");
+        let key = self.cache_key()?;
+        // Only the query template gets logged here since filter values may be sensitive.
+        info!(
+            origin = self.origin,
+            source = self.source,
+            query = self.query,
+            "Running upstream promql query"
+        );
+        let Some(ttl) = self.cache_ttl() else {
+            return self.fetch(&key).await;
+        };
+        if let Some((results, refresh)) = cached_result(&key) {
+            debug!(origin = self.origin, refresh, "Using cached promql results");
+            if refresh {
+                match self.take_budget() {
+                    Ok(()) => spawn_refresh(
+                        key,
+                        self.origin.to_string(),
+                        self.timeout,
+                        ttl,
+                        self.cache_stale(),
+                    ),
+                    Err(err) => {
+                        // The stale result is better than nothing. A later request refreshes it.
+                        debug!(origin = self.origin, %err, "Skipping refresh over the query budget");
+                        end_refresh(&key);
+                    }
+                }
+            }
+            return Ok(results);
+        }
+        let results = self.fetch(&key).await?;
+        cache_result(key, ttl, self.cache_stale(), &results);
+        Ok(results)
+    }
+}
+
+impl<'conn> PromQueryConn<'conn> {
+    /// Runs the query as an instant query at each of the times and collects the values into a
+    /// series per label set with one point per time.
+    pub async fn get_snapshots(&self, times: &[DateTime<Utc>]) -> anyhow::Result<MetricsQueryResult> {
+        let query = self.get_query();
+        debug!(?query, snapshots = times.len(), "Using promql query for snapshots");
+        info!(
+            origin = self.origin,
+            source = self.source,
+            query = self.query,
+            "Running upstream promql snapshot queries"
+        );
+        let mut series: Vec<(HashMap<String, String>, PlotConfig, Vec<DataPoint>)> = Vec::new();
+        for time in times {
+            let results = self
+                .fetch(&self.instant_key(query.clone(), time.timestamp())?)
+                .await?;
+            let points = match prom_to_samples(results.data().clone(), self.meta.clone()) {
+                MetricsQueryResult::Scalar(points) => points,
+                MetricsQueryResult::Series(_) | MetricsQueryResult::Histogram(_) => {
+                    return Err(anyhow::anyhow!(
+                        "Snapshot query returned a range result: {}",
+                        self.query
+                    ))
+                }
+            };
+            for (labels, meta, point) in points {
+                match series.iter_mut().find(|(l, _, _)| *l == labels) {
+                    Some((_, _, trace)) => trace.push(point),
+                    None => series.push((labels, meta, vec![point])),
+                }
             }
-            QueryType::Scalar => Ok(client.query(&query).get().await?),
         }
+        Ok(MetricsQueryResult::Series(series))
+    }
+
+    /// Runs the query and collects the native histogram samples of every series. Histogram
+    /// results aren't cached.
+    pub async fn get_histograms(&self) -> anyhow::Result<MetricsQueryResult> {
+        let key = self.cache_key()?;
+        info!(
+            origin = self.origin,
+            source = self.source,
+            query = self.query,
+            "Running upstream promql histogram query"
+        );
+        self.take_budget()?;
+        let data: RawHistogramData = observe_query(
+            self.source,
+            &self.query_type,
+            with_timeout(self.timeout, async {
+                parse_response(key.fetch_raw(self.origin).await?).await
+            }),
+        )
+        .await?;
+        let mut series = Vec::with_capacity(data.result.len());
+        for raw in data.result {
+            let points = raw
+                .histograms
+                .into_iter()
+                .chain(raw.histogram)
+                .map(|(timestamp, histogram)| histogram.into_point(timestamp))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            series.push((raw.metric, self.meta.clone(), points));
+        }
+        Ok(MetricsQueryResult::Histogram(series))
+    }
+
+    /// Estimates the cost of this query by counting the series it currently returns.
+    pub async fn get_estimate(&self) -> anyhow::Result<QueryEstimate> {
+        let query = format!("count({})", self.get_query());
+        debug!(?query, "Estimating promql query");
+        info!(
+            origin = self.origin,
+            source = self.source,
+            query = self.query,
+            "Running upstream promql estimate query"
+        );
+        let results = self
+            .fetch(&self.instant_key(query, Utc::now().timestamp())?)
+            .await?;
+        let series = match results.data() {
+            Data::Vector(vector) => vector.first().map(|v| v.sample().value()).unwrap_or(0.0),
+            Data::Scalar(sample) => sample.value(),
+            Data::Matrix(_) => 0.0,
+        } as usize;
+        let points_per_series = match self.query_type {
+            QueryType::Range => match self.span {
+                Some(TimeSpan { duration, .. }) => duration.num_seconds() / self.step_seconds().max(1),
+                // The default span is 10 minutes at a 30 second step.
+                None => 20,
+            },
+            QueryType::Scalar => 1,
+        };
+        Ok(QueryEstimate {
+            series,
+            points_per_series,
+        })
+    }
+}
+
+/// Scales the value and clamps it to zero if the plot asks for it. Clamped values are counted.
+fn sample_value(meta: &PlotConfig, value: f64, clamped: &mut usize) -> f64 {
+    let value = meta.scale_value(value);
+    if meta.clamp_negative() && value < 0.0 {
+        *clamped += 1;
+        return 0.0;
     }
+    value
 }
 
 pub fn prom_to_samples(data: Data, meta: PlotConfig) -> MetricsQueryResult {
-    match data {
+    let mut clamped = 0;
+    let result = match data {
         Data::Matrix(mut range) => MetricsQueryResult::Series(
             range
                 .drain(0..)
@@ -173,7 +1003,8 @@ pub fn prom_to_samples(data: Data, meta: PlotConfig) -> MetricsQueryResult {
                             .drain(0..)
                             .map(|s| DataPoint {
                                 timestamp: s.timestamp(),
-                                value: s.value(),
+                                value: sample_value(&meta, s.value(), &mut clamped),
+                                color: None,
                             })
                             .collect(),
                     )
@@ -190,7 +1021,8 @@ pub fn prom_to_samples(data: Data, meta: PlotConfig) -> MetricsQueryResult {
                         meta.clone(),
                         DataPoint {
                             timestamp: sample.timestamp(),
-                            value: sample.value(),
+                            value: sample_value(&meta, sample.value(), &mut clamped),
+                            color: None,
                         },
                     )
                 })
@@ -201,8 +1033,357 @@ pub fn prom_to_samples(data: Data, meta: PlotConfig) -> MetricsQueryResult {
             meta.clone(),
             DataPoint {
                 timestamp: sample.timestamp(),
-                value: sample.value(),
+                value: sample_value(&meta, sample.value(), &mut clamped),
+                color: None,
             },
         )]),
+    };
+    if clamped > 0 {
+        debug!(clamped, "Clamped negative values to zero");
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use axum::{
+        extract::Query,
+        http::{header, Method},
+        routing::get,
+        Form, Json, Router,
+    };
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::query::test_server::{gzip, serve, PROM_MATRIX};
+
+    /// Answers range queries with one series labeled with the query it was asked for and
+    /// counts the requests.
+    fn echo_query_router(hits: Arc<AtomicUsize>) -> Router {
+        Router::new().route(
+            "/api/v1/query_range",
+            get(
+                move |Query(params): Query<HashMap<String, String>>| async move {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    Json(serde_json::json!({
+                        "status": "success",
+                        "data": {
+                            "resultType": "matrix",
+                            "result": [{
+                                "metric": {"query": params.get("query")},
+                                "values": [[1700000000, "1"]],
+                            }],
+                        },
+                    }))
+                },
+            ),
+        )
+    }
+
+    fn fixed_span_conn<'conn>(source: &'conn str, query: &'conn str) -> PromQueryConn<'conn> {
+        PromQueryConn::new(source, query, QueryType::Range, PlotConfig::default()).with_span(
+            Utc.timestamp_opt(1700000000, 0).unwrap(),
+            chrono::Duration::hours(1),
+            chrono::Duration::seconds(30),
+        )
+    }
+
+    fn parse_data(json: &str) -> Data {
+        serde_json::from_str::<PromqlResult>(json)
+            .expect("Invalid test data")
+            .data()
+            .clone()
+    }
+
+    /// The query label of every series in the result.
+    async fn result_queries(conn: PromQueryConn<'_>) -> Vec<String> {
+        let results = conn.get_results().await.expect("Query should succeed");
+        match prom_to_samples(results.data().clone(), PlotConfig::default()) {
+            MetricsQueryResult::Series(series) => series
+                .into_iter()
+                .map(|(labels, _, _)| labels["query"].clone())
+                .collect(),
+            _ => panic!("Expected a range result"),
+        }
+    }
+
+    #[tokio::test]
+    async fn gzip_encoded_responses_are_decoded() {
+        let source = serve(Router::new().route(
+            "/api/v1/query_range",
+            get(|| async {
+                (
+                    [
+                        (header::CONTENT_TYPE, "application/json"),
+                        (header::CONTENT_ENCODING, "gzip"),
+                    ],
+                    gzip(PROM_MATRIX.as_bytes()),
+                )
+            }),
+        ))
+        .await;
+        let conn = PromQueryConn::new(&source, "up", QueryType::Range, PlotConfig::default());
+        let results = conn.get_results().await.expect("gzip response should parse");
+        match prom_to_samples(results.data().clone(), PlotConfig::default()) {
+            MetricsQueryResult::Series(series) => {
+                assert_eq!(series.len(), 1);
+                assert_eq!(series[0].0["job"], "api");
+                assert_eq!(series[0].2.len(), 2);
+            }
+            _ => panic!("Expected a range result"),
+        }
+    }
+
+    #[tokio::test]
+    async fn filters_are_part_of_the_cache_key() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let source = serve(echo_query_router(hits.clone())).await;
+        let unfiltered = HashMap::new();
+        let filtered = HashMap::from([("job", "api")]);
+        let query = |filters| {
+            fixed_span_conn(&source, "up{FILTERS}")
+                .with_cache_seconds(Some(60))
+                .with_filters(filters)
+        };
+
+        assert_eq!(result_queries(query(&unfiltered)).await, vec!["up{}"]);
+        assert_eq!(
+            result_queries(query(&filtered)).await,
+            vec![r#"up{job=~"api"}"#]
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        // Both results are cached separately.
+        assert_eq!(result_queries(query(&unfiltered)).await, vec!["up{}"]);
+        assert_eq!(
+            result_queries(query(&filtered)).await,
+            vec![r#"up{job=~"api"}"#]
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn reordered_filters_render_the_same_cache_key() {
+        let labels = ["job", "instance", "region", "zone", "env"];
+        let forward = labels.iter().map(|l| (*l, "x")).collect::<HashMap<_, _>>();
+        let mut backward = HashMap::new();
+        for label in labels.iter().rev() {
+            backward.insert(*label, "x");
+        }
+        let forward = fixed_span_conn("http://localhost", "up{FILTERS}").with_filters(&forward);
+        let backward = fixed_span_conn("http://localhost", "up{FILTERS}").with_filters(&backward);
+        assert_eq!(
+            forward.get_query(),
+            r#"up{env=~"x",instance=~"x",job=~"x",region=~"x",zone=~"x"}"#
+        );
+        assert_eq!(forward.cache_key().unwrap(), backward.cache_key().unwrap());
+    }
+
+    #[test]
+    fn scale_converts_bytes_to_megabytes() {
+        let meta: PlotConfig = serde_yaml::from_str("scale: 0.00000095367431640625").unwrap();
+        let data = parse_data(
+            r#"{"resultType":"matrix","result":[{"metric":{},"values":[[1700000000,"1048576"],[1700000030,"5242880"]]}]}"#,
+        );
+        match prom_to_samples(data, meta.clone()) {
+            MetricsQueryResult::Series(series) => {
+                let values = series[0].2.iter().map(DataPoint::value).collect::<Vec<_>>();
+                assert_eq!(values, vec![1.0, 5.0]);
+            }
+            _ => panic!("Expected a range result"),
+        }
+        let data = parse_data(r#"{"resultType":"scalar","result":[1700000000,"3145728"]}"#);
+        match prom_to_samples(data, meta) {
+            MetricsQueryResult::Scalar(values) => assert_eq!(values[0].2.value(), 3.0),
+            _ => panic!("Expected an instant result"),
+        }
+        let identity = parse_data(r#"{"resultType":"scalar","result":[1700000000,"1048576"]}"#);
+        match prom_to_samples(identity, PlotConfig::default()) {
+            MetricsQueryResult::Scalar(values) => assert_eq!(values[0].2.value(), 1048576.0),
+            _ => panic!("Expected an instant result"),
+        }
+    }
+
+    #[tokio::test]
+    async fn queries_are_sent_under_the_source_base_path() {
+        let source = serve(Router::new().route(
+            "/prometheus/api/v1/query_range",
+            get(|| async { ([(header::CONTENT_TYPE, "application/json")], PROM_MATRIX) }),
+        ))
+        .await;
+        for source in [format!("{}/prometheus", source), format!("{}/prometheus/", source)] {
+            let conn = PromQueryConn::new(&source, "up", QueryType::Range, PlotConfig::default());
+            conn.get_results().await.expect("prefixed query should succeed");
+        }
+    }
+
+    #[test]
+    fn interval_placeholder_matches_the_query_step() {
+        let filters = HashMap::from([("job", "api")]);
+        let spans = [
+            Some((chrono::Duration::hours(1), chrono::Duration::seconds(30))),
+            Some((chrono::Duration::minutes(5), chrono::Duration::seconds(1))),
+            // A step that gets raised to stay under the max points.
+            Some((chrono::Duration::days(30), chrono::Duration::seconds(1))),
+            None,
+        ];
+        for span in spans {
+            let conn = PromQueryConn::new(
+                "http://localhost",
+                "rate(requests{FILTERS}[$__interval])",
+                QueryType::Range,
+                PlotConfig::default(),
+            )
+            .with_filters(&filters);
+            let conn = match span {
+                Some((duration, step)) => {
+                    conn.with_span(Utc.timestamp_opt(1700000000, 0).unwrap(), duration, step)
+                }
+                None => conn,
+            };
+            let key = conn.cache_key().unwrap();
+            assert_eq!(key.step_seconds, conn.step_seconds());
+            assert_eq!(
+                key.query,
+                format!(r#"rate(requests{{job=~"api"}}[{}s])"#, key.step_seconds)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn long_queries_are_posted() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let record = |requests: Arc<Mutex<Vec<(Method, String)>>>, method| {
+            move |params: HashMap<String, String>| async move {
+                requests.lock().unwrap().push((method, params["query"].clone()));
+                ([(header::CONTENT_TYPE, "application/json")], PROM_MATRIX)
+            }
+        };
+        let get_query = record(requests.clone(), Method::GET);
+        let post_query = record(requests.clone(), Method::POST);
+        let source = serve(Router::new().route(
+            "/api/v1/query_range",
+            get(move |Query(params)| get_query(params))
+                .post(move |Form(params)| post_query(params)),
+        ))
+        .await;
+        let long = format!("sum({})", vec!["up"; DEFAULT_POST_QUERY_LENGTH / 4].join(" + "));
+        assert!(long.len() > DEFAULT_POST_QUERY_LENGTH);
+        for query in ["up", long.as_str()] {
+            PromQueryConn::new(&source, query, QueryType::Range, PlotConfig::default())
+                .get_results()
+                .await
+                .expect("Query should succeed");
+        }
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![(Method::GET, "up".to_string()), (Method::POST, long)]
+        );
+    }
+
+    #[test]
+    fn filters_are_joined_with_commas() {
+        let two = HashMap::from([("job", "foo"), ("instance", "bar")]);
+        let none = HashMap::new();
+        let cases = [
+            ("up{FILTERS}", &two, r#"up{instance=~"bar",job=~"foo"}"#),
+            ("up{env=\"prod\",FILTERS}", &two, r#"up{env="prod",instance=~"bar",job=~"foo"}"#),
+            ("up{FILTERS,env=\"prod\"}", &two, r#"up{instance=~"bar",job=~"foo",env="prod"}"#),
+            ("up{env=\"prod\",FILTERS}", &none, r#"up{env="prod"}"#),
+            ("up{FILTERS,env=\"prod\"}", &none, r#"up{env="prod"}"#),
+        ];
+        for (query, filters, expected) in cases {
+            let conn = PromQueryConn::new(
+                "http://localhost",
+                query,
+                QueryType::Range,
+                PlotConfig::default(),
+            )
+            .with_filters(filters);
+            assert_eq!(conn.get_query(), expected);
+        }
+    }
+
+    #[test]
+    fn scope_matchers_cant_be_bypassed_by_filters() {
+        let scope = [r#"team="a""#.to_string()];
+        // The scope always renders first and filters only add matchers to it. Every matcher
+        // has to match so none of these widen the scope. Invalid labels are dropped and quotes
+        // in values are escaped so nothing can close the selector early.
+        let hostile = [
+            ("team", ".*", r#"sum(up{team="a",team=~".*"})"#),
+            ("neq-team", "a", r#"sum(up{team="a",team!="a"})"#),
+            ("exact-team", "b", r#"sum(up{team="a",team="b"})"#),
+            (r#"team"}"#, "x", r#"sum(up{team="a"})"#),
+            ("job", r#"x"} or up{team="b"#, r#"sum(up{team="a",job=~"x\"} or up{team=\"b"})"#),
+        ];
+        for (key, value, expected) in hostile {
+            let filters = HashMap::from([(key, value)]);
+            let conn = fixed_span_conn("http://localhost", "sum(up{FILTERS})")
+                .with_scope(&scope)
+                .with_filters(&filters);
+            assert_eq!(conn.get_query(), expected);
+        }
+    }
+
+    #[test]
+    fn escape_label_value_escapes_quotes_backslashes_and_newlines() {
+        assert_eq!(escape_label_value(r#"foo"bar"#), r#"foo\"bar"#);
+        assert_eq!(escape_label_value(r"foo\bar"), r"foo\\bar");
+        assert_eq!(escape_label_value("foo\nbar\r"), r"foo\nbar\r");
+        assert_eq!(escape_label_value("plain"), "plain");
+    }
+
+    #[test]
+    fn filter_values_are_escaped_in_the_query() {
+        let filters = HashMap::from([("job", r#"foo"bar"#), ("instance", r"a\")]);
+        let conn = fixed_span_conn("http://localhost", "up{FILTERS}").with_filters(&filters);
+        assert_eq!(conn.get_query(), r#"up{instance=~"a\\",job=~"foo\"bar"}"#);
+    }
+
+    #[test]
+    fn filter_prefixes_pick_the_match_operator() {
+        assert_eq!(filter_matcher("job", "api|web").unwrap(), r#"job=~"api|web""#);
+        assert_eq!(filter_matcher("exact-job", "api").unwrap(), r#"job="api""#);
+        assert_eq!(filter_matcher("neq-job", "api").unwrap(), r#"job!="api""#);
+        // Prefixes only apply once and the rest still has to be a valid label.
+        assert_eq!(filter_matcher("exact-neq-job", "api"), None);
+        assert_eq!(filter_matcher("exact-", "api"), None);
+        assert_eq!(filter_matcher("neq-", "api"), None);
+        assert_eq!(filter_matcher("", "api"), None);
+        assert_eq!(filter_matcher("1job", "api"), None);
+        assert_eq!(filter_matcher("_job", "api").unwrap(), r#"_job=~"api""#);
+    }
+
+    #[test]
+    fn every_selector_filtered_checks_each_selector() {
+        for query in [
+            "up{FILTERS}",
+            r#"sum by (job) (rate(http_requests{code="500",FILTERS}[$__interval]))"#,
+            "histogram_quantile(0.9, sum(rate(latency_bucket{FILTERS}[5m])) BY (le))",
+            r#"a{FILTERS} / on(instance) group_left b{FILTERS} offset 5m > bool 0.5"#,
+            r#"label_replace(up{FILTERS}, "dst", "$1", "src", "(.*) or secret")"#,
+            "vector(1)",
+        ] {
+            assert!(every_selector_filtered(query), "{}", query);
+        }
+        for query in [
+            "a{FILTERS} / b",
+            "up{FILTERS} or secret_metric",
+            "up{FILTERS} or secret_metric{job=\"x\"}",
+            "sum(rate(errors{FILTERS}[5m])) / sum(rate(requests[5m]))",
+            r#"{__name__="up"}"#,
+        ] {
+            assert!(!every_selector_filtered(query), "{}", query);
+        }
+    }
+
+    #[test]
+    fn prefixed_filters_render_in_the_query() {
+        let filters = HashMap::from([("exact-job", "api"), ("neq-instance", "a"), ("env", "p.*")]);
+        let conn = fixed_span_conn("http://localhost", "up{FILTERS}").with_filters(&filters);
+        assert_eq!(conn.get_query(), r#"up{env=~"p.*",job="api",instance!="a"}"#);
     }
 }
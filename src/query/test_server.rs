@@ -0,0 +1,63 @@
+// Copyright 2024 Jeremy Wall
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Local mock upstreams and sample data for the tests.
+use std::io::Write;
+use std::net::SocketAddr;
+
+use axum::Router;
+use flate2::{write::GzEncoder, Compression};
+use tokio::net::TcpListener;
+
+/// Serves the router on a free local port and returns its base url. Handlers can extract the
+/// client's address with `ConnectInfo<SocketAddr>`.
+pub async fn serve(router: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Unable to bind the test server");
+    let addr = listener.local_addr().expect("Test server has no address");
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+    });
+    format!("http://{}", addr)
+}
+
+/// Gzip compresses the body.
+pub fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body).expect("Unable to gzip the body");
+    encoder.finish().expect("Unable to gzip the body")
+}
+
+/// A successful prometheus matrix response with one series.
+pub const PROM_MATRIX: &str = r#"{"status":"success","data":{"resultType":"matrix","result":[{"metric":{"job":"api"},"values":[[1700000000,"1"],[1700000030,"2"]]}]}}"#;
+
+/// Start of the sample series.
+pub const START: i64 = 1700000000;
+
+/// A series with one point per `step` seconds from START.
+pub fn points(step: i64, values: &[f64]) -> Vec<super::DataPoint> {
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| super::DataPoint {
+            timestamp: (START + step * i as i64) as f64,
+            value: *value,
+            color: None,
+        })
+        .collect()
+}
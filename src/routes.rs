@@ -11,11 +11,18 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+};
 
 use axum::{
+    body::{Body, Bytes},
     extract::{Path, Query, State},
-    response::Response,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Redirect, Response},
     routing::get,
     Json, Router,
 };
@@ -23,15 +30,283 @@ use axum::{
 // https://maud.lambda.xyz/getting-started.html
 use maud::{html, Markup};
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use tracing::{debug, error, info};
 
 use crate::dashboard::{
-    loki_query_data, prom_query_data, AxisDefinition, Dashboard, Graph, GraphSpan, Orientation, LogStream,
+    key_metric_data, loki_query_data, menu_titles, prom_estimate_data, prom_query_data, slo_data,
+    source_health, Annotation, AxisDefinition, ChartType, Dashboard, FilterView, Graph, GraphSpan,
+    KeyMetric, LogStream, Orientation, Playlist, ScalarDisplay, SourceHealth, SloStatus,
 };
-use crate::query::{self, MetricsQueryResult, LogQueryResult};
+use crate::query::{self, MetricsQueryResult, LogQueryResult, QueryEstimate, TableRow};
 
 type Config = State<Arc<Vec<Dashboard>>>;
 
+/// Errors from the api handlers. They are sent as a status code with a json body of the form
+/// `{"error": "..."}`.
+#[derive(Debug)]
+pub enum AppError {
+    /// A dashboard, graph, or log index that doesn't exist.
+    NotFound(String),
+    /// A query param that doesn't fit the graph. e.g. an unknown source name.
+    BadRequest(String),
+    /// The request is missing a scope header or the graph can't be scoped.
+    Forbidden(String),
+    /// The upstream source rate limited the query.
+    RateLimited(query::RateLimited),
+    /// The upstream query took longer than its timeout.
+    TimedOut(query::QueryTimedOut),
+    /// The dashboard used up its upstream query budget.
+    OverBudget(query::QueryBudgetExceeded),
+    /// The upstream query failed.
+    Upstream(anyhow::Error),
+    /// Building the response failed.
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<query::RateLimited>() {
+            Ok(limited) => return AppError::RateLimited(limited),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<query::QueryBudgetExceeded>() {
+            Ok(exceeded) => return AppError::OverBudget(exceeded),
+            Err(err) => err,
+        };
+        match err.downcast::<query::QueryTimedOut>() {
+            Ok(timed_out) => AppError::TimedOut(timed_out),
+            Err(err) => AppError::Upstream(err),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let (status, error) = match &self {
+            AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg.clone()),
+            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
+            AppError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg.clone()),
+            AppError::RateLimited(limited) => (StatusCode::TOO_MANY_REQUESTS, limited.to_string()),
+            AppError::TimedOut(timed_out) => (StatusCode::GATEWAY_TIMEOUT, timed_out.to_string()),
+            AppError::OverBudget(exceeded) => (StatusCode::TOO_MANY_REQUESTS, exceeded.to_string()),
+            AppError::Upstream(err) => {
+                error!(?err, "Upstream query failed");
+                (StatusCode::BAD_GATEWAY, format!("{:#}", err))
+            }
+            AppError::Internal(err) => {
+                error!(?err, "Building the response failed");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+            }
+        };
+        let mut response = (status, Json(ErrorBody { error })).into_response();
+        // Pass rate limiting through so the client can back off.
+        let retry_after = match self {
+            AppError::RateLimited(query::RateLimited { retry_after }) => retry_after,
+            AppError::OverBudget(exceeded) => Some(exceeded.retry_after),
+            _ => None,
+        };
+        if let Some(secs) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
+    }
+}
+
+fn get_dash(config: &[Dashboard], dash_idx: usize) -> Result<&Dashboard, AppError> {
+    config
+        .get(dash_idx)
+        .ok_or_else(|| AppError::NotFound(format!("No such dashboard index {}", dash_idx)))
+}
+
+fn get_graph(dash: &Dashboard, dash_idx: usize, graph_idx: usize) -> Result<&Graph, AppError> {
+    dash.graphs
+        .as_ref()
+        .and_then(|graphs| graphs.get(graph_idx))
+        .ok_or_else(|| {
+            AppError::NotFound(format!("No such graph {} in dashboard {}", graph_idx, dash_idx))
+        })
+}
+
+fn get_log(dash: &Dashboard, dash_idx: usize, log_idx: usize) -> Result<&LogStream, AppError> {
+    dash.logs
+        .as_ref()
+        .and_then(|logs| logs.get(log_idx))
+        .ok_or_else(|| {
+            AppError::NotFound(format!("No such log {} in dashboard {}", log_idx, dash_idx))
+        })
+}
+
+/// Maps a request header to a label matcher added to every prometheus query. Parsed from
+/// `<label>=<header>`. e.g. `team=X-Auth-Team`.
+#[derive(Clone, Debug)]
+pub struct ScopeLabel {
+    pub label: String,
+    pub header: String,
+}
+
+impl std::str::FromStr for ScopeLabel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((label, header)) if !label.is_empty() && !header.is_empty() => Ok(ScopeLabel {
+                label: label.to_string(),
+                header: header.to_string(),
+            }),
+            _ => Err(format!("Expected <label>=<header> but got {}", s)),
+        }
+    }
+}
+
+static SCOPE_LABELS: OnceLock<Vec<ScopeLabel>> = OnceLock::new();
+
+/// Sets the headers that scope every prometheus query. Must be called before serving.
+pub fn set_scope_labels(labels: Vec<ScopeLabel>) {
+    if SCOPE_LABELS.set(labels).is_err() {
+        error!("Scope labels were already set");
+    }
+}
+
+pub fn scope_labels() -> &'static [ScopeLabel] {
+    SCOPE_LABELS.get().map(Vec::as_slice).unwrap_or(&[])
+}
+
+/// The scope matchers for the request. Requests missing a scope header are denied rather than
+/// left unscoped.
+fn request_scope(headers: &HeaderMap) -> Result<Vec<String>, AppError> {
+    let mut scope = Vec::new();
+    for ScopeLabel { label, header } in scope_labels() {
+        let value = headers
+            .get(header)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| AppError::Forbidden(format!("Missing the {} header", header)))?;
+        scope.push(format!("{}=\"{}\"", label, query::escape_label_value(value)));
+    }
+    Ok(scope)
+}
+
+/// The scope matchers for a request to a graph. Graphs that can't be scoped are refused while
+/// scoping is on.
+fn graph_scope(graph: &Graph, headers: &HeaderMap) -> Result<Vec<String>, AppError> {
+    let scope = request_scope(headers)?;
+    if !scope.is_empty() && !graph.scopable() {
+        return Err(AppError::Forbidden(format!(
+            "Graph {} has queries without a FILTERS placeholder so it can't be scoped",
+            graph.title
+        )));
+    }
+    Ok(scope)
+}
+
+/// Log streams can't be scoped so they are refused while scoping is on.
+fn log_scope(log: &LogStream, headers: &HeaderMap) -> Result<(), AppError> {
+    if !request_scope(headers)?.is_empty() {
+        return Err(AppError::Forbidden(format!("Log stream {} can't be scoped", log.title)));
+    }
+    Ok(())
+}
+
+/// The named source selected by the `source` query param if there is one. None means every
+/// plot uses its default source.
+fn query_to_source<'a>(
+    graph: &Graph,
+    query: &'a HashMap<String, String>,
+) -> Result<Option<&'a str>, AppError> {
+    let Some(name) = query.get("source") else {
+        return Ok(None);
+    };
+    graph
+        .check_source_name(name)
+        .map_err(|err| AppError::BadRequest(err.to_string()))?;
+    Ok(Some(name))
+}
+
+/// The version 2 media type. Send it in the Accept header, or pass `v=2`, to get a
+/// `PayloadV2` instead of the plain v1 payload.
+pub const V2_MEDIA_TYPE: &str = "application/vnd.heracles.v2+json";
+const V1_MEDIA_TYPE: &str = "application/vnd.heracles.v1+json";
+
+/// The shape of the query api responses. v1 is the default so existing frontends keep working.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiVersion {
+    V1,
+    V2,
+}
+
+/// The requested api version. A `v` query param wins over the Accept header.
+fn api_version(
+    headers: &HeaderMap,
+    query: &HashMap<String, String>,
+) -> Result<ApiVersion, AppError> {
+    if let Some(v) = query.get("v") {
+        return match v.as_str() {
+            "1" => Ok(ApiVersion::V1),
+            "2" => Ok(ApiVersion::V2),
+            _ => Err(AppError::BadRequest(format!("Unsupported api version {}", v))),
+        };
+    }
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let requested = |media_type| {
+        accept
+            .split(',')
+            .any(|part| part.split(';').next().unwrap_or_default().trim() == media_type)
+    };
+    if requested(V2_MEDIA_TYPE) && !requested(V1_MEDIA_TYPE) {
+        Ok(ApiVersion::V2)
+    } else {
+        Ok(ApiVersion::V1)
+    }
+}
+
+/// The version 2 response. It wraps the v1 payload unchanged in `data` and adds what a client
+/// needs to interpret it.
+#[derive(Serialize, Deserialize)]
+pub struct PayloadV2 {
+    pub version: u32,
+    /// Unix timestamp the results were generated at.
+    pub generated_at: i64,
+    /// The step the range queries used. It can be coarser than configured to stay under
+    /// prometheus' point limit. None for logs and instant queries.
+    pub step_seconds: Option<i64>,
+    pub data: QueryPayload,
+}
+
+fn versioned_response(version: ApiVersion, payload: QueryPayload) -> Response {
+    match version {
+        ApiVersion::V1 => Json(payload).into_response(),
+        ApiVersion::V2 => {
+            let step_seconds = match &payload {
+                QueryPayload::Metrics(graph) => graph.step_seconds,
+                QueryPayload::Logs(_) => None,
+            };
+            let mut response = Json(PayloadV2 {
+                version: 2,
+                generated_at: chrono::Utc::now().timestamp(),
+                step_seconds,
+                data: payload,
+            })
+            .into_response();
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static(V2_MEDIA_TYPE));
+            response
+        }
+    }
+}
+
+/// The v1 query payload. This is the contract existing frontends rely on: it is an object with
+/// a single `Metrics` or `Logs` key and the fields below keep their names and meaning. New
+/// fields may be added but nothing is removed or changed. Anything else goes in `PayloadV2`.
 #[derive(Serialize, Deserialize)]
 pub enum QueryPayload {
     Metrics(GraphPayload),
@@ -43,11 +318,36 @@ pub struct GraphPayload {
     pub legend_orientation: Option<Orientation>,
     pub yaxes: Vec<AxisDefinition>,
     pub plots: Vec<MetricsQueryResult>,
+    pub scalar_display: ScalarDisplay,
+    pub gauge_min: Option<f64>,
+    pub gauge_max: Option<f64>,
+    pub annotations: Vec<Annotation>,
+    /// Draw series as markers only. Set for snapshot graphs.
+    pub markers: bool,
+    /// One row per series for table charts.
+    pub table: Option<Vec<TableRow>>,
+    /// Things the viewer should know about the results. e.g. series hidden by a filter.
+    pub warnings: Vec<String>,
+    /// Set when the plots hold an instant query's current value because the range was empty.
+    pub fallback: bool,
+    /// Error budget status for Slo panels.
+    pub slo: Option<SloStatus>,
+    /// High resolution results for the end of the span when `plots` is a coarse overview.
+    pub detail: Option<Vec<MetricsQueryResult>>,
+    /// Only sent in `PayloadV2`.
+    #[serde(skip)]
+    pub step_seconds: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct LogsPayload {
     pub lines: LogQueryResult,
+    /// Set when the result probably got cut off at the limit. Loki stops returning lines once it
+    /// reaches the limit so a result with exactly `limit` lines is assumed to have more. A
+    /// result that happens to be exactly `limit` lines long gets flagged too.
+    #[serde(default)]
+    pub truncated: bool,
+    pub limit: Option<usize>,
 }
 
 // TODO(jwall): Should this be a completely different payload?
@@ -55,21 +355,30 @@ pub async fn loki_query(
     State(config): Config,
     Path((dash_idx, loki_idx)): Path<(usize, usize)>,
     Query(query): Query<HashMap<String, String>>,
-) -> Json<QueryPayload> {
-    let dash = config
-        .get(dash_idx)
-        .expect(&format!("No such dashboard index {}", dash_idx));
-    let log = dash
-        .logs
-        .as_ref()
-        .expect("No logs in this dashboard")
-        .get(loki_idx)
-        .expect(&format!("No such log query {}", loki_idx));
-    let lines = loki_query_data(log, dash, query_to_graph_span(&query))
-        .await
-        .expect("Unable to get log query results");
-    Json(QueryPayload::Logs(LogsPayload {
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let version = api_version(&headers, &query)?;
+    let payload = log_payload(&config, dash_idx, loki_idx, &query, &headers).await?;
+    Ok(versioned_response(version, payload))
+}
+
+async fn log_payload(
+    config: &[Dashboard],
+    dash_idx: usize,
+    loki_idx: usize,
+    query: &HashMap<String, String>,
+    headers: &HeaderMap,
+) -> Result<QueryPayload, AppError> {
+    let dash = get_dash(config, dash_idx)?;
+    let log = get_log(dash, dash_idx, loki_idx)?;
+    log_scope(log, headers)?;
+    let origin = format!("dashboard={} log={}", dash_idx, loki_idx);
+    let lines = loki_query_data(log, dash, query_to_graph_span(query), &origin).await?;
+    let truncated = log.limit.is_some_and(|limit| lines.line_count() >= limit);
+    Ok(QueryPayload::Logs(LogsPayload {
         lines,
+        truncated,
+        limit: log.limit,
     }))
 }
 
@@ -77,34 +386,292 @@ pub async fn graph_query(
     State(config): Config,
     Path((dash_idx, graph_idx)): Path<(usize, usize)>,
     Query(query): Query<HashMap<String, String>>,
-) -> Json<QueryPayload> {
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let version = api_version(&headers, &query)?;
+    let payload = graph_payload(&config, dash_idx, graph_idx, &query, &headers).await?;
+    Ok(versioned_response(version, payload))
+}
+
+async fn graph_payload(
+    config: &[Dashboard],
+    dash_idx: usize,
+    graph_idx: usize,
+    query: &HashMap<String, String>,
+    headers: &HeaderMap,
+) -> Result<QueryPayload, AppError> {
     debug!("Getting data for query");
-    let dash = config
-        .get(dash_idx)
-        .expect(&format!("No such dashboard index {}", dash_idx));
-    let graph = dash
-        .graphs
-        .as_ref()
-        .expect("No graphs in this dashboard")
-        .get(graph_idx)
-        .expect(&format!("No such graph in dasboard {}", dash_idx));
-    let filters = query_to_filterset(&query);
-    let plots = prom_query_data(graph, dash, query_to_graph_span(&query), &filters)
-        .await
-        .expect("Unable to get query results");
-    Json(QueryPayload::Metrics(GraphPayload {
+    let dash = get_dash(config, dash_idx)?;
+    let graph = get_graph(dash, dash_idx, graph_idx)?;
+    let scope = graph_scope(graph, headers)?;
+    let filters = query_to_filterset(query);
+    let source = query_to_source(graph, query)?;
+    let query_span = query_to_graph_span(query);
+    let annotations = graph.annotations_in_span(dash, &query_span);
+    let origin = format!("dashboard={} graph={}", dash_idx, graph_idx);
+    let (data, detail) = match graph.overview_detail_spans(dash, &query_span) {
+        Some((overview_span, detail_span)) => {
+            let (overview, detail) = futures::join!(
+                prom_query_data(graph, dash, Some(overview_span), &filters, source, &scope, &origin),
+                prom_query_data(graph, dash, Some(detail_span), &filters, source, &scope, &origin),
+            );
+            (overview?, Some(detail?.plots))
+        }
+        None => (
+            prom_query_data(graph, dash, query_span, &filters, source, &scope, &origin).await?,
+            None,
+        ),
+    };
+    let mut plots = data.plots;
+    let mut detail = detail;
+    // Only an explicit non empty name_format overrides the configured one.
+    if let Some(name_format) = query.get("name_format").filter(|f| !f.is_empty()) {
+        for plot in plots.iter_mut().chain(detail.iter_mut().flatten()) {
+            plot.set_name_format(name_format);
+        }
+    }
+    for plot in plots.iter_mut().chain(detail.iter_mut().flatten()) {
+        plot.resolve_names();
+    }
+    let slo = slo_data(graph, &origin).await?;
+    let table = match graph.chart_type {
+        ChartType::Table => Some(
+            plots
+                .iter()
+                .flat_map(|plot| plot.table_rows(&graph.table_labels))
+                .collect(),
+        ),
+        ChartType::Plot => None,
+    };
+    Ok(QueryPayload::Metrics(GraphPayload {
         legend_orientation: graph.legend_orientation.clone(),
         yaxes: graph.yaxes.clone(),
         plots,
+        scalar_display: graph.scalar_display.clone(),
+        gauge_min: graph.gauge_min,
+        gauge_max: graph.gauge_max,
+        annotations,
+        markers: graph.is_snapshot() || data.fallback,
+        table,
+        warnings: data.warnings,
+        fallback: data.fallback,
+        slo,
+        detail,
+        step_seconds: data.step_seconds,
     }))
 }
 
+/// Returns a per plot estimate of the series count and points per series for a graph.
+pub async fn graph_estimate(
+    State(config): Config,
+    Path((dash_idx, graph_idx)): Path<(usize, usize)>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<QueryEstimate>>, AppError> {
+    let dash = get_dash(&config, dash_idx)?;
+    let graph = get_graph(dash, dash_idx, graph_idx)?;
+    let scope = graph_scope(graph, &headers)?;
+    let filters = query_to_filterset(&query);
+    let source = query_to_source(graph, &query)?;
+    let origin = format!("dashboard={} graph={}", dash_idx, graph_idx);
+    let estimates =
+        prom_estimate_data(graph, dash, query_to_graph_span(&query), &filters, source, &scope, &origin).await?;
+    Ok(Json(estimates))
+}
+
+/// Returns the graph's results as a parquet file for loading into analysis tools.
+pub async fn graph_parquet(
+    State(config): Config,
+    Path((dash_idx, graph_idx)): Path<(usize, usize)>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, AppError> {
+    let dash = get_dash(&config, dash_idx)?;
+    let graph = get_graph(dash, dash_idx, graph_idx)?;
+    let scope = graph_scope(graph, &headers)?;
+    let filters = query_to_filterset(&query);
+    let source = query_to_source(graph, &query)?;
+    let origin = format!("dashboard={} graph={} export=parquet", dash_idx, graph_idx);
+    let data = prom_query_data(graph, dash, query_to_graph_span(&query), &filters, source, &scope, &origin).await?;
+    let parquet = query::metrics_to_parquet(&data.plots).map_err(AppError::Internal)?;
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/vnd.apache.parquet")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"dash-{}-graph-{}.parquet\"",
+                dash_idx, graph_idx
+            ),
+        )
+        .body(Body::from(parquet))
+        .map_err(|err| AppError::Internal(err.into()))
+}
+
+/// Returns the current values of every key metric graph across all the dashboards.
+pub async fn key_metrics(
+    State(config): Config,
+    headers: HeaderMap,
+) -> Result<Json<Vec<KeyMetric>>, AppError> {
+    let scope = request_scope(&headers)?;
+    Ok(Json(key_metric_data(&config, &scope).await))
+}
+
+/// Liveness probe. Answers as long as the process is serving requests.
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+static ADMIN_TOKEN: OnceLock<String> = OnceLock::new();
+
+/// Sets the bearer token the admin endpoints require. They refuse every request until it is
+/// set. Must be called before serving.
+pub fn set_admin_token(token: String) {
+    if ADMIN_TOKEN.set(token).is_err() {
+        error!("Admin token was already set");
+    }
+}
+
+/// Refuses the request unless it carries `Authorization: Bearer <admin token>`.
+fn check_admin(headers: &HeaderMap) -> Result<(), AppError> {
+    let Some(token) = ADMIN_TOKEN.get() else {
+        return Err(AppError::Forbidden(
+            "The admin endpoints are disabled without --admin-token-env".to_string(),
+        ));
+    };
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if bearer != Some(token.as_str()) {
+        return Err(AppError::Forbidden("Missing or invalid admin token".to_string()));
+    }
+    Ok(())
+}
+
+/// Limits a cache invalidation. With neither set the whole cache is cleared. A dashboard
+/// clears the results of every source its graphs query, including results other dashboards
+/// share with it. Setting both clears only the source and only if the dashboard queries it.
+#[derive(Deserialize, Default)]
+pub struct InvalidateParams {
+    pub source: Option<String>,
+    pub dashboard: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct InvalidateResult {
+    pub evicted: usize,
+}
+
+/// Drops cached query results so the next request queries the sources again. e.g. after a
+/// backfill. The optional json body is an `InvalidateParams`.
+pub async fn invalidate_cache(
+    State(config): Config,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<InvalidateResult>, AppError> {
+    check_admin(&headers)?;
+    let params: InvalidateParams = if body.is_empty() {
+        InvalidateParams::default()
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|err| AppError::BadRequest(format!("Invalid invalidation params: {}", err)))?
+    };
+    let source = params.source.as_deref().map(|s| s.trim_end_matches('/'));
+    let dash_sources = match params.dashboard {
+        Some(dash_idx) => Some(get_dash(&config, dash_idx)?.prom_sources()),
+        None => None,
+    };
+    let evicted = query::invalidate_cached_results(|key_source| {
+        source.is_none_or(|source| source == key_source)
+            && dash_sources
+                .as_ref()
+                .is_none_or(|sources| sources.contains(&key_source))
+    });
+    info!(
+        source = params.source,
+        dashboard = params.dashboard,
+        evicted,
+        "Invalidated cached query results"
+    );
+    Ok(Json(InvalidateResult { evicted }))
+}
+
+/// Upstream query counts, errors, and latency by source and query_type in the prometheus text
+/// exposition format so heracles can be scraped by the prometheus it queries.
+pub async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        query::render_query_metrics(),
+    )
+}
+
+/// Readiness probe. Checks every configured source and reports each one's status. Responds
+/// with 503 if any source is unreachable.
+pub async fn readyz(State(config): Config) -> (StatusCode, Json<Vec<SourceHealth>>) {
+    let sources = source_health(&config).await;
+    let status = if sources.iter().all(|source| source.healthy) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(sources))
+}
+
+/// Default number of seconds between long poll refreshes.
+pub const DEFAULT_POLL_SECONDS: i64 = 30;
+/// The longest a long poll request will wait before returning fresh data.
+pub const MAX_POLL_WAIT_SECONDS: i64 = 60;
+
+/// Long poll version of graph_query for clients that can't use websockets. It waits until
+/// `interval` seconds (default 30) after the `since` unix timestamp before querying and
+/// returning fresh data. The wait is capped at MAX_POLL_WAIT_SECONDS.
+pub async fn graph_poll(
+    State(config): Config,
+    Path((dash_idx, graph_idx)): Path<(usize, usize)>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let version = api_version(&headers, &query)?;
+    // Bad indices 404 right away instead of after the wait.
+    get_graph(get_dash(&config, dash_idx)?, dash_idx, graph_idx)?;
+    let now = chrono::Utc::now().timestamp();
+    let since = query
+        .get("since")
+        .and_then(|s| s.parse::<i64>().ok())
+        .unwrap_or(now);
+    let interval = query
+        .get("interval")
+        .and_then(|s| s.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_POLL_SECONDS);
+    let wait = poll_wait(since, interval, now);
+    debug!(since, interval, wait, "Waiting for next long poll refresh");
+    tokio::time::sleep(std::time::Duration::from_secs(wait as u64)).await;
+    let payload = graph_payload(&config, dash_idx, graph_idx, &query, &headers).await?;
+    Ok(versioned_response(version, payload))
+}
+
+/// Seconds until `interval` after `since`, clamped to MAX_POLL_WAIT_SECONDS. The params come
+/// from the client so the math saturates instead of overflowing.
+fn poll_wait(since: i64, interval: i64, now: i64) -> i64 {
+    since
+        .saturating_add(interval)
+        .saturating_sub(now)
+        .clamp(0, MAX_POLL_WAIT_SECONDS)
+}
+
+/// The `filter-<label>` query params. The label can carry an `exact-` or `neq-` prefix to pick
+/// the match operator. See `PromQueryConn::with_filters`.
 fn query_to_filterset<'v, 'a: 'v>(query: &'a HashMap<String, String>) -> Option<HashMap<&'v str, &'v str>> {
     debug!(query_params=?query, "Filtering query params to filter requests");
     let mut label_set = HashMap::new();
     for (k, v) in query.iter() {
         if k.starts_with("filter-") {
             if let Some(label) = k.strip_prefix("filter-") {
+                // An empty label or value would render an invalid matcher so skip them.
+                if label.is_empty() || v.is_empty() {
+                    debug!(param = k, value = v, "Skipping filter with an empty label or value");
+                    continue;
+                }
                 label_set.insert(label, v.as_str());
             }
         }
@@ -116,15 +683,17 @@ fn query_to_filterset<'v, 'a: 'v>(query: &'a HashMap<String, String>) -> Option<
     }
 }
 
+/// The span from the `end`, `step_duration` and either `start` or `duration` query params.
 fn query_to_graph_span<'a>(query: &'a HashMap<String, String>) -> Option<GraphSpan> {
     let query_span = {
         if query.contains_key("end")
-            && query.contains_key("duration")
+            && (query.contains_key("duration") || query.contains_key("start"))
             && query.contains_key("step_duration")
         {
             Some(GraphSpan {
                 end: query["end"].clone(),
-                duration: query["duration"].clone(),
+                start: query.get("start").cloned(),
+                duration: query.get("duration").cloned().unwrap_or_default(),
                 step_duration: query["step_duration"].clone(),
             })
         } else {
@@ -137,10 +706,23 @@ fn query_to_graph_span<'a>(query: &'a HashMap<String, String>) -> Option<GraphSp
 pub fn mk_api_routes(config: Arc<Vec<Dashboard>>) -> Router<Config> {
     // Query routes
     Router::new()
+        .route("/key-metrics", get(key_metrics).with_state(config.clone()))
         .route(
             "/dash/:dash_idx/graph/:graph_idx",
             get(graph_query).with_state(config.clone()),
         )
+        .route(
+            "/dash/:dash_idx/graph/:graph_idx/estimate",
+            get(graph_estimate).with_state(config.clone()),
+        )
+        .route(
+            "/dash/:dash_idx/graph/:graph_idx/parquet",
+            get(graph_parquet).with_state(config.clone()),
+        )
+        .route(
+            "/dash/:dash_idx/graph/:graph_idx/poll",
+            get(graph_poll).with_state(config.clone()),
+        )
         .route(
             "/dash/:dash_idx/log/:log_idx",
             get(loki_query).with_state(config),
@@ -151,27 +733,113 @@ pub fn log_component(dash_idx: usize, log_idx: usize, log: &LogStream) -> Markup
     let log_id = format!("log-{}-{}", dash_idx, log_idx);
     let log_data_uri = format!("/api/dash/{}/log/{}", dash_idx, log_idx);
     let log_embed_uri = format!("/embed/dash/{}/log/{}", dash_idx, log_idx);
+    let log_ui_uri = format!("/ui/dash/{}/log/{}", dash_idx, log_idx);
+    html! {
+        div id=(format!("{}-panel", log_id)) class="panel" {
+            h2 { (log.title) " - " a href=(log_embed_uri) { "embed url" } " " (panel_reload(&log_id, &log_ui_uri)) }
+            div class="panel-error" { }
+            div class="panel-warning" { }
+            log-plot uri=(log_data_uri) id=(log_id) pause-when-hidden=(log.pause_when_hidden) { }
+        }
+    }
+}
+
+/// An optional tab group name and the indexed log streams in it.
+type LogSection<'a> = (Option<&'a str>, Vec<(usize, &'a LogStream)>);
+
+/// Tabbed section for log streams that share a tab group. Only the first stream is rendered up
+/// front. The other tabs fetch their panel from the ui route when selected so unselected
+/// streams never query their source.
+fn log_tab_group(
+    dash_idx: usize,
+    group_idx: usize,
+    name: &str,
+    logs: &[(usize, &LogStream)],
+) -> Markup {
+    let content_id = format!("log-tabs-{}-{}", dash_idx, group_idx);
     html! {
-        div {
-            h2 { (log.title) " - " a href=(log_embed_uri) { "embed url" } }
-            log-plot uri=(log_data_uri) id=(log_id) { }
+        div class="log-tab-group" {
+            h2 { (name) }
+            div class="row-flex log-tabs" {
+                @for (tab_idx, (log_idx, log)) in logs.iter().enumerate() {
+                    button class=(if tab_idx == 0 { "log-tab active" } else { "log-tab" })
+                        hx-get=(format!("/ui/dash/{}/log/{}", dash_idx, log_idx))
+                        hx-target=(format!("#{}", content_id))
+                        hx-swap="innerHTML" { (log.title) }
+                }
+            }
+            div id=(content_id) {
+                @if let Some((log_idx, log)) = logs.first() {
+                    (log_component(dash_idx, *log_idx, log))
+                }
+            }
         }
     }
 }
 
-pub fn graph_component(dash_idx: usize, graph_idx: usize, graph: &Graph) -> Markup {
+/// Link that reloads a panel through its ui route. The loading indicator is a sibling of the
+/// link rather than part of the custom element so it doesn't interfere with the element's
+/// initialization.
+fn panel_reload(id: &str, ui_uri: &str) -> Markup {
+    let loading_id = format!("{}-loading", id);
+    html! {
+        a hx-get=(ui_uri) hx-target=(format!("#{}-panel", id)) hx-swap="outerHTML" hx-indicator=(format!("#{}", loading_id)) { "reload" }
+        " "
+        span id=(loading_id) class="htmx-indicator" { "Loading..." }
+    }
+}
+
+fn plot_fallback(data_uri: &str) -> Markup {
+    html! {
+        "Unable to render this graph. " a href=(data_uri) { "View the raw data" }
+    }
+}
+
+pub fn graph_component(dash_idx: usize, graph_idx: usize, dash: &Dashboard, graph: &Graph, view: Option<&FilterView>) -> Markup {
+    graph_component_with_payload(dash_idx, graph_idx, dash, graph, view, None)
+}
+
+/// Renders the graph component. A `payload` is the serialized QueryPayload the element renders
+/// first instead of fetching it.
+fn graph_component_with_payload(
+    dash_idx: usize,
+    graph_idx: usize,
+    dash: &Dashboard,
+    graph: &Graph,
+    view: Option<&FilterView>,
+    payload: Option<&str>,
+) -> Markup {
+    let payload_hook = dash.custom_js_src(dash_idx);
     let graph_id = format!("graph-{}-{}", dash_idx, graph_idx);
-    let graph_data_uri = format!("/api/dash/{}/graph/{}", dash_idx, graph_idx);
+    let mut graph_data_uri = format!("/api/dash/{}/graph/{}", dash_idx, graph_idx);
     let graph_embed_uri = format!("/embed/dash/{}/graph/{}", dash_idx, graph_idx);
     let allow_filters = graph.plots.iter().find(|p| p.query.contains(query::FILTER_PLACEHOLDER)).is_some();
+    if let (true, Some(view)) = (allow_filters, view) {
+        let mut filters = view
+            .filters
+            .iter()
+            .map(|(k, v)| (format!("filter-{}", k), v.as_str()))
+            .collect::<Vec<(String, &str)>>();
+        // Keep the uri stable across renders.
+        filters.sort();
+        if let Ok(params) = serde_urlencoded::to_string(filters) {
+            graph_data_uri.push('?');
+            graph_data_uri.push_str(&params);
+        }
+    }
+    let graph_ui_uri = match view.map(|v| serde_urlencoded::to_string([("view", &v.name)])) {
+        Some(Ok(params)) => format!("/ui/dash/{}/graph/{}?{}", dash_idx, graph_idx, params),
+        _ => format!("/ui/dash/{}/graph/{}", dash_idx, graph_idx),
+    };
     html!(
-        div {
-            h2 { (graph.title) " - " a href=(graph_embed_uri) { "embed url" } }
-            @if graph.d3_tick_format.is_some() {
-                graph-plot allow-uri-filters=(allow_filters) uri=(graph_data_uri) id=(graph_id) d3-tick-format=(graph.d3_tick_format.as_ref().unwrap()) { }
-            } @else {
-                graph-plot allow-uri-filters=(allow_filters) uri=(graph_data_uri) id=(graph_id) { }
-            }
+        div id=(format!("{}-panel", graph_id)) class="panel" {
+            h2 { (graph.title) " - " a href=(graph_embed_uri) { "embed url" } " " (panel_reload(&graph_id, &graph_ui_uri)) }
+            div class="panel-error" { }
+            div class="panel-warning" { }
+            graph-plot allow-uri-filters=(allow_filters) uri=(graph_data_uri) id=(graph_id) pause-when-hidden=(graph.pause_when_hidden) d3-tick-format=[graph.tick_format(dash)] payload=[payload] payload-hook=[payload_hook.as_ref()] { }
+            noscript { (plot_fallback(&graph_data_uri)) }
+            // Shown by the graph-plot element if plotly failed to load.
+            div class="plot-fallback" hidden { (plot_fallback(&graph_data_uri)) }
         }
     )
 }
@@ -179,42 +847,73 @@ pub fn graph_component(dash_idx: usize, graph_idx: usize, graph: &Graph) -> Mark
 pub async fn graph_ui(
     State(config): State<Config>,
     Path((dash_idx, graph_idx)): Path<(usize, usize)>,
-) -> Markup {
-    let graph = config
-        .get(dash_idx)
-        .expect(&format!("No such dashboard {}", dash_idx))
-        .graphs
-        .as_ref()
-        .expect("No graphs in this dashboard")
-        .get(graph_idx)
-        .expect("No such graph");
-    graph_component(dash_idx, graph_idx, graph)
+    Query(query): Query<HashMap<String, String>>,
+) -> Result<Markup, AppError> {
+    let dash = get_dash(&config, dash_idx)?;
+    let graph = get_graph(dash, dash_idx, graph_idx)?;
+    let view = query.get("view").and_then(|name| dash.get_view(name));
+    Ok(graph_component(dash_idx, graph_idx, dash, graph, view))
+}
+
+/// The graph component with the query results inlined so it renders without a fetch. Any
+/// filters from the view are applied to the inlined query like they are to the data uri.
+pub async fn graph_inline(
+    State(config): State<Config>,
+    Path((dash_idx, graph_idx)): Path<(usize, usize)>,
+    Query(mut query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Markup, AppError> {
+    let dash = get_dash(&config, dash_idx)?;
+    let graph = get_graph(dash, dash_idx, graph_idx)?;
+    let view = query.get("view").and_then(|name| dash.get_view(name));
+    let allow_filters = graph.plots.iter().any(|p| p.query.contains(query::FILTER_PLACEHOLDER));
+    if let (true, Some(view)) = (allow_filters, view) {
+        for (k, v) in view.filters.iter() {
+            query.insert(format!("filter-{}", k), v.clone());
+        }
+    }
+    let payload = graph_payload(&config, dash_idx, graph_idx, &query, &headers).await?;
+    let payload = serde_json::to_string(&payload).map_err(|err| AppError::Internal(err.into()))?;
+    Ok(graph_component_with_payload(dash_idx, graph_idx, dash, graph, view, Some(&payload)))
 }
 
 pub async fn log_ui(
     State(config): State<Config>,
     Path((dash_idx, log_idx)): Path<(usize, usize)>,
-) -> Markup {
-    let log = config
-        .get(dash_idx)
-        .expect(&format!("No such dashboard {}", dash_idx))
-        .logs
-        .as_ref()
-        .expect("No graphs in this dashboard")
-        .get(log_idx)
-        .expect("No such graph");
-    log_component(dash_idx, log_idx, log)
+) -> Result<Markup, AppError> {
+    let log = get_log(get_dash(&config, dash_idx)?, dash_idx, log_idx)?;
+    Ok(log_component(dash_idx, log_idx, log))
 }
 
-pub async fn dash_ui(State(config): State<Config>, Path(dash_idx): Path<usize>) -> Markup {
-    // TODO(zaphar): Should do better http error reporting here.
-    dash_elements(config, dash_idx)
+pub async fn dash_ui(
+    State(config): State<Config>,
+    Path(dash_idx): Path<usize>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Result<Markup, AppError> {
+    dash_elements(config, dash_idx, query.get("view").map(String::as_str))
 }
 
-fn dash_elements(config: State<Arc<Vec<Dashboard>>>, dash_idx: usize) -> maud::PreEscaped<String> {
-    let dash = config
-        .get(dash_idx)
-        .expect(&format!("No such dashboard {}", dash_idx));
+fn view_uri(prefix: &str, dash_idx: usize, view: Option<&str>) -> String {
+    match view.map(|v| serde_urlencoded::to_string([("view", v)])) {
+        Some(Ok(params)) => format!("{}/dash/{}?{}", prefix, dash_idx, params),
+        _ => format!("{}/dash/{}", prefix, dash_idx),
+    }
+}
+
+fn view_selector(dash_idx: usize, views: &Vec<FilterView>) -> Markup {
+    html! {
+        div class="row-flex" {
+            button hx-push-url=(view_uri("", dash_idx, None)) hx-get=(view_uri("/ui", dash_idx, None)) hx-target="#dashboard" { "All" }
+            @for view in views {
+                button hx-push-url=(view_uri("", dash_idx, Some(&view.name))) hx-get=(view_uri("/ui", dash_idx, Some(&view.name))) hx-target="#dashboard" { (view.name) }
+            }
+        }
+    }
+}
+
+fn dash_elements(config: State<Arc<Vec<Dashboard>>>, dash_idx: usize, view: Option<&str>) -> Result<Markup, AppError> {
+    let dash = get_dash(&config, dash_idx)?;
+    let view = view.and_then(|name| dash.get_view(name));
     let graph_components = if let Some(graphs) = dash
         .graphs
         .as_ref() {
@@ -223,28 +922,45 @@ fn dash_elements(config: State<Arc<Vec<Dashboard>>>, dash_idx: usize) -> maud::P
         .collect::<Vec<(usize, &Graph)>>();
         Some(html! {
             @for (idx, graph) in &graph_iter {
-                (graph_component(dash_idx, *idx, *graph))
+                (graph_component(dash_idx, *idx, dash, *graph, view))
             }
         })
     } else {
         None
     };
     let log_components = if let Some(logs) = dash.logs.as_ref() {
-        let log_iter = logs.iter().enumerate().collect::<Vec<(usize, &LogStream)>>();
+        // Tab groups render where their first stream appears in the config.
+        let mut sections: Vec<LogSection> = Vec::new();
+        for (idx, log) in logs.iter().enumerate() {
+            let group = log.tab_group.as_deref();
+            match sections.iter_mut().find(|(name, _)| group.is_some() && *name == group) {
+                Some((_, streams)) => streams.push((idx, log)),
+                None => sections.push((group, vec![(idx, log)])),
+            }
+        }
         Some(html! {
-            @for (idx, log) in &log_iter {
-                (log_component(dash_idx, *idx, *log))
+            @for (section_idx, (group, streams)) in sections.iter().enumerate() {
+                @if let Some(name) = group {
+                    (log_tab_group(dash_idx, section_idx, name, streams))
+                } @else {
+                    @for (idx, log) in streams {
+                        (log_component(dash_idx, *idx, *log))
+                    }
+                }
             }
         })
     } else {
         None
     };
-    html!(
+    Ok(html!(
+        // Part of the swapped in dashboard so it changes along with the dashboard.
+        (custom_assets(dash_idx, Some(dash)))
         h1 { (dash.title) }
+        @if let Some(views) = dash.views.as_ref() { (view_selector(dash_idx, views)) }
         span-selector class="row-flex" {}
-        @if graph_components.is_some() { (graph_components.unwrap()) }
-        @if log_components.is_some() { (log_components.unwrap()) }
-    )
+        @if let Some(graph_components) = graph_components { (graph_components) }
+        @if let Some(log_components) = log_components { (log_components) }
+    ))
 }
 
 pub fn mk_ui_routes(config: Arc<Vec<Dashboard>>) -> Router<Config> {
@@ -255,139 +971,696 @@ pub fn mk_ui_routes(config: Arc<Vec<Dashboard>>) -> Router<Config> {
         )
         .route(
             "/dash/:dash_idx/graph/:graph_idx",
-            get(graph_ui).with_state(State(config)),
+            get(graph_ui).with_state(State(config.clone())),
+        )
+        .route(
+            "/dash/:dash_idx/graph/:graph_idx/inline",
+            get(graph_inline).with_state(State(config.clone())),
+        )
+        .route(
+            "/dash/:dash_idx/log/:log_idx",
+            get(log_ui).with_state(State(config)),
         )
 }
 
 fn graph_lib_prelude() -> Markup {
+    let paths = asset_paths();
+    html! {
+        script src=(format!("/js/{}", paths.plotly)) { }
+        script type="module" defer src=(format!("/js/{}", paths.lib)) {  }
+        link rel="stylesheet" href=(format!("/static/{}", paths.site_css)) {  }
+    }
+}
+
+/// The dashboard's custom stylesheet and payload hook module.
+fn custom_assets(dash_idx: usize, dash: Option<&Dashboard>) -> Markup {
     html! {
-        script src="/js/plotly.js" { }
-        script type="module" defer src="/js/lib.mjs" {  }
-        link rel="stylesheet" href="/static/site.css" {  }
+        @if let Some(href) = dash.and_then(|d| d.custom_css_href(dash_idx)) {
+            link rel="stylesheet" href=(href) {  }
+        }
+        @if let Some(src) = dash.and_then(|d| d.custom_js_src(dash_idx)) {
+            link rel="modulepreload" href=(src) {  }
+        }
+    }
+}
+
+/// OpenGraph tags so shared embed links render a preview.
+fn embed_meta(panel_title: &str, dash: Option<&Dashboard>) -> Markup {
+    let description = match dash {
+        Some(dash) => format!("{} from the {} dashboard in Heracles", panel_title, dash.title),
+        None => format!("{} in Heracles", panel_title),
+    };
+    html! {
+        meta property="og:type" content="website";
+        meta property="og:site_name" content="Heracles";
+        meta property="og:title" content=(panel_title);
+        meta property="og:description" content=(description);
     }
 }
 
 pub async fn graph_embed(
     State(config): State<Config>,
     Path((dash_idx, graph_idx)): Path<(usize, usize)>,
-) -> Markup {
-    html! {
+) -> Result<Markup, AppError> {
+    let component = graph_ui(State(config.clone()), Path((dash_idx, graph_idx)), Query(HashMap::new())).await?;
+    let dash = config.get(dash_idx);
+    let graph_title = dash
+        .and_then(|d| d.graphs.as_ref())
+        .and_then(|g| g.get(graph_idx))
+        .map(|g| g.title.as_str())
+        .unwrap_or("Graph");
+    Ok(html! {
         html {
             head {
                 title { ("Heracles - Prometheus Unshackled") }
+                (embed_meta(graph_title, dash))
             }
             body {
                 (graph_lib_prelude())
-                (graph_ui(State(config.clone()), Path((dash_idx, graph_idx))).await)
+                (custom_assets(dash_idx, dash))
+                (component)
             }
         }
-    }
+    })
 }
 
 pub async fn log_embed(
     State(config): State<Config>,
     Path((dash_idx, log_idx)): Path<(usize, usize)>,
-) -> Markup {
-    html! {
+) -> Result<Markup, AppError> {
+    let component = log_ui(State(config.clone()), Path((dash_idx, log_idx))).await?;
+    let dash = config.get(dash_idx);
+    let log_title = dash
+        .and_then(|d| d.logs.as_ref())
+        .and_then(|l| l.get(log_idx))
+        .map(|l| l.title.as_str())
+        .unwrap_or("Logs");
+    Ok(html! {
         html {
             head {
                 title { ("Heracles - Prometheus Unshackled") }
+                (embed_meta(log_title, dash))
             }
             body {
                 (graph_lib_prelude())
-                (log_ui(State(config.clone()), Path((dash_idx, log_idx))).await)
+                (custom_assets(dash_idx, dash))
+                (component)
             }
         }
-    }
+    })
 }
 
-async fn index_html(config: Config, dash_idx: Option<usize>) -> Markup {
-    html! {
+async fn index_html(config: Config, dash_idx: Option<usize>, view: Option<&str>) -> Result<Markup, AppError> {
+    let app = app(State(config.clone()), dash_idx, view).await?;
+    Ok(html! {
         html {
             head {
                 title { ("Heracles - Prometheus Unshackled") }
             }
             body {
-                script src="/js/htmx.js" {  }
+                script src=(format!("/js/{}", asset_paths().htmx)) {  }
                 (graph_lib_prelude())
-                (app(State(config.clone()), dash_idx).await)
+                (app)
             }
         }
-    }
+    })
 }
 
-pub async fn index(State(config): State<Config>) -> Markup {
-    index_html(config, None).await
+pub async fn index(State(config): State<Config>) -> Result<Markup, AppError> {
+    index_html(config, None, None).await
+}
+
+pub async fn dashboard_direct(
+    State(config): State<Config>,
+    Path(dash_idx): Path<usize>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Result<Markup, AppError> {
+    index_html(config, Some(dash_idx), query.get("view").map(String::as_str)).await
 }
 
-pub async fn dashboard_direct(State(config): State<Config>, Path(dash_idx): Path<usize>) -> Markup {
-    index_html(config, Some(dash_idx)).await
+/// What the playlist-rotator element needs to show a playlist item.
+#[derive(Serialize)]
+struct PlaylistSlide {
+    /// The dashboard's ui fragment.
+    uri: String,
+    dwell_seconds: i64,
 }
 
-fn render_index(config: State<Arc<Vec<Dashboard>>>, dash_idx: Option<usize>) -> Markup {
-    let titles = config
+/// A page that rotates through a playlist's dashboards. The rotation runs in the browser from
+/// the slides the server renders into the page.
+pub async fn playlist(
+    State(playlists): State<Arc<Vec<Playlist>>>,
+    Path(name): Path<String>,
+) -> Result<Markup, AppError> {
+    let playlist = playlists
         .iter()
-        .map(|d| d.title.clone())
-        .enumerate()
-        .collect::<Vec<(usize, String)>>();
-    html! {
+        .find(|p| p.name == name)
+        .ok_or_else(|| AppError::NotFound(format!("No such playlist {}", name)))?;
+    let slides = playlist
+        .items
+        .iter()
+        .map(|item| PlaylistSlide {
+            uri: view_uri("/ui", item.dashboard, item.view.as_deref()),
+            dwell_seconds: item.dwell_seconds(),
+        })
+        .collect::<Vec<PlaylistSlide>>();
+    let slides = serde_json::to_string(&slides).expect("Unable to serialize playlist");
+    Ok(html! {
+        html {
+            head {
+                title { ("Heracles - ") (playlist.name) }
+            }
+            body {
+                script src=(format!("/js/{}", asset_paths().htmx)) {  }
+                (graph_lib_prelude())
+                playlist-rotator slides=(slides) loop=(playlist.looping) target="#dashboard" { }
+                div id="dashboard" { }
+            }
+        }
+    })
+}
+
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets the config path shown when there are no dashboards to help track down a bad path.
+pub fn set_config_path(path: &std::path::Path) {
+    let _ = CONFIG_PATH.set(path.to_path_buf());
+}
+
+fn render_index(config: State<Arc<Vec<Dashboard>>>, dash_idx: Option<usize>, view: Option<&str>) -> Result<Markup, AppError> {
+    if config.is_empty() {
+        return Ok(html! {
+            div class="panel" {
+                h1 { "No dashboards configured" }
+                @if let Some(path) = CONFIG_PATH.get() {
+                    p { "The config file " code { (path.display()) } " has no dashboards in it." }
+                }
+            }
+        });
+    }
+    let titles = menu_titles(&config);
+    let dash = dash_idx
+        .map(|dash_idx| dash_elements(config.clone(), dash_idx, view))
+        .transpose()?;
+    Ok(html! {
         div class="row-flex" {
             div class="flex-item-shrink" {
                 // Header menu
                 ul {
                     @for title in &titles {
-                        li hx-push-url=(format!("/dash/{}", title.0)) hx-get=(format!("/ui/dash/{}", title.0)) hx-target="#dashboard" { (title.1) }
+                        li hx-push-url=(format!("/dash/{}", title.0)) hx-get=(format!("/ui/dash/{}", title.0)) hx-target="#dashboard" hx-indicator="#dashboard-loading" { (title.1) }
                     }
                 }
+                span id="dashboard-loading" class="htmx-indicator" { "Loading..." }
+                div id="dashboard-error" class="panel-error" { }
             }
             div class="flex-item-grow" id="dashboard" {
-                @if let Some(dash_idx) = dash_idx {
-                    (dash_elements(config, dash_idx))
+                @if let Some(dash) = dash {
+                    (dash)
                 }
             }
         }
-    }
+    })
 }
 
-pub async fn app(State(config): State<Config>, dash_idx: Option<usize>) -> Markup {
-    render_index(config, dash_idx)
+pub async fn app(State(config): State<Config>, dash_idx: Option<usize>, view: Option<&str>) -> Result<Markup, AppError> {
+    render_index(config, dash_idx, view)
 }
 
-pub fn javascript_response(content: &str) -> Response<String> {
-    Response::builder()
-        .header("Content-Type", "text/javascript")
-        .body(content.to_string())
-        .expect("Invalid javascript response")
+const HTMX_JS: &str = include_str!("../static/htmx.min.js");
+const PLOTLY_JS: &str = include_str!("../static/plotly-2.27.0.min.js");
+const LIB_JS: &str = include_str!("../static/lib.mjs");
+const SITE_CSS: &str = include_str!("../static/site.css");
+
+/// Assets are served under content hashed paths so browsers can cache them forever and still
+/// fetch fresh copies after an upgrade.
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Content hashed file names of the static assets.
+pub struct AssetPaths {
+    pub htmx: String,
+    pub plotly: String,
+    pub lib: String,
+    pub site_css: String,
+}
+
+/// `name.<hash>.ext` for the content.
+fn hashed_name(name: &str, ext: &str, content: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    format!("{}.{:016x}.{}", name, hasher.finish(), ext)
+}
+
+/// The hashes are computed from the embedded assets the first time this is called. That
+/// happens when the routes are built at startup.
+pub fn asset_paths() -> &'static AssetPaths {
+    static PATHS: OnceLock<AssetPaths> = OnceLock::new();
+    PATHS.get_or_init(|| AssetPaths {
+        htmx: hashed_name("htmx", "js", HTMX_JS),
+        plotly: hashed_name("plotly", "js", PLOTLY_JS),
+        lib: hashed_name("lib", "mjs", LIB_JS),
+        site_css: hashed_name("site", "css", SITE_CSS),
+    })
+}
+
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            parts.next() == Some("gzip") && !parts.any(|p| p == "q=0" || p == "q=0.0")
+        })
+}
+
+/// Serves the gzipped copy of a javascript asset built by build.rs to clients that accept it
+/// and the raw content to everyone else.
+pub fn gzipped_javascript_response(
+    headers: &HeaderMap,
+    content: &'static str,
+    gzipped: &'static [u8],
+) -> Response<Body> {
+    let builder = Response::builder()
+        .header(header::CONTENT_TYPE, "text/javascript")
+        .header(header::CACHE_CONTROL, IMMUTABLE_CACHE_CONTROL)
+        .header(header::VARY, "Accept-Encoding");
+    if accepts_gzip(headers) {
+        builder
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(Body::from(gzipped))
+    } else {
+        builder.body(Body::from(content))
+    }
+    .expect("Invalid javascript response")
 }
 
 // TODO(jwall): Should probably hook in one of the axum directory serving crates here.
-pub async fn htmx() -> Response<String> {
-    javascript_response(include_str!("../static/htmx.min.js"))
+pub async fn htmx(headers: HeaderMap) -> Response<Body> {
+    gzipped_javascript_response(
+        &headers,
+        HTMX_JS,
+        include_bytes!(concat!(env!("OUT_DIR"), "/htmx.min.js.gz")),
+    )
 }
 
-pub async fn plotly() -> Response<String> {
-    javascript_response(include_str!("../static/plotly-2.27.0.min.js"))
+pub async fn plotly(headers: HeaderMap) -> Response<Body> {
+    gzipped_javascript_response(
+        &headers,
+        PLOTLY_JS,
+        include_bytes!(concat!(env!("OUT_DIR"), "/plotly-2.27.0.min.js.gz")),
+    )
 }
 
-pub async fn lib() -> Response<String> {
-    javascript_response(include_str!("../static/lib.mjs"))
+pub async fn lib(headers: HeaderMap) -> Response<Body> {
+    gzipped_javascript_response(
+        &headers,
+        LIB_JS,
+        include_bytes!(concat!(env!("OUT_DIR"), "/lib.mjs.gz")),
+    )
 }
 
 pub fn mk_js_routes(config: Arc<Vec<Dashboard>>) -> Router<Config> {
+    let paths = asset_paths();
     Router::new()
-        .route("/plotly.js", get(plotly))
-        .route("/lib.mjs", get(lib))
-        .route("/htmx.js", get(htmx))
+        .route(&format!("/{}", paths.plotly), get(plotly))
+        .route(&format!("/{}", paths.lib), get(lib))
+        .route(&format!("/{}", paths.htmx), get(htmx))
+        // The unhashed paths stay around for anything that still links to them. The redirects
+        // are temporary so they don't get cached past an upgrade.
+        .route("/plotly.js", get(|| async { Redirect::temporary(&format!("/js/{}", asset_paths().plotly)) }))
+        .route("/lib.mjs", get(|| async { Redirect::temporary(&format!("/js/{}", asset_paths().lib)) }))
+        .route("/htmx.js", get(|| async { Redirect::temporary(&format!("/js/{}", asset_paths().htmx)) }))
         .with_state(State(config))
 }
 
+/// Serves a dashboard's file based custom_css.
+pub async fn dash_custom_css(
+    State(config): State<Config>,
+    Path(dash_idx): Path<usize>,
+) -> Result<Response<Body>, AppError> {
+    let css = get_dash(&config, dash_idx)?
+        .custom_css_content
+        .clone()
+        .ok_or_else(|| AppError::NotFound(format!("No custom css for dashboard {}", dash_idx)))?;
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/css")
+        .body(Body::from(css))
+        .map_err(|err| AppError::Internal(err.into()))
+}
+
+/// Serves a dashboard's custom_js payload hook module.
+pub async fn dash_custom_js(
+    State(config): State<Config>,
+    Path(dash_idx): Path<usize>,
+) -> Result<Response<Body>, AppError> {
+    let js = get_dash(&config, dash_idx)?
+        .custom_js_content
+        .clone()
+        .ok_or_else(|| AppError::NotFound(format!("No custom js for dashboard {}", dash_idx)))?;
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/javascript")
+        .body(Body::from(js))
+        .map_err(|err| AppError::Internal(err.into()))
+}
+
 pub fn mk_static_routes(config: Arc<Vec<Dashboard>>) -> Router<Config> {
     Router::new()
         .route(
-            "/site.css",
+            "/dash/:dash_idx/custom.js",
+            get(dash_custom_js).with_state(State(config.clone())),
+        )
+        .route(
+            "/dash/:dash_idx/custom.css",
+            get(dash_custom_css).with_state(State(config.clone())),
+        )
+        .route(
+            &format!("/{}", asset_paths().site_css),
             get(|| async {
-                return include_str!("../static/site.css");
+                (
+                    [
+                        (header::CONTENT_TYPE, "text/css"),
+                        (header::CACHE_CONTROL, IMMUTABLE_CACHE_CONTROL),
+                    ],
+                    SITE_CSS,
+                )
             }),
         )
+        .route(
+            "/site.css",
+            get(|| async { Redirect::temporary(&format!("/static/{}", asset_paths().site_css)) }),
+        )
         .with_state(State(config))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use axum::{routing::get, Router};
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::query::test_server::serve;
+
+    async fn body_bytes(response: Response<Body>) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Unable to read the body")
+            .to_vec()
+    }
+
+    fn dashboards(yaml: &str) -> Vec<Dashboard> {
+        serde_yaml::from_str(yaml).expect("Invalid test dashboards")
+    }
+
+    const SECRET: &str = "hunter2";
+
+    /// A prometheus and loki upstream whose only series and stream carry the SECRET in a token
+    /// label.
+    async fn secret_upstream() -> String {
+        const MATRIX: &str = r#"{"status":"success","data":{"resultType":"matrix","result":[{"metric":{"job":"api","token":"hunter2"},"values":[[1700000000,"1"],[1700000030,"2"]]}]}}"#;
+        const STREAMS: &str = r#"{"status":"success","data":{"resultType":"streams","result":[{"stream":{"job":"api","token":"hunter2"},"values":[["1700000000000000000","line"]]}]}}"#;
+        let json = |body: &'static str| async move {
+            ([(header::CONTENT_TYPE, "application/json")], body)
+        };
+        serve(
+            Router::new()
+                .route("/api/v1/query_range", get(move || json(MATRIX)))
+                .route("/loki/api/v1/query_range", get(move || json(STREAMS))),
+        )
+        .await
+    }
+
+    fn contains(haystack: &[u8], needle: &str) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle.as_bytes())
+    }
+
+    fn accept_encoding(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn javascript_is_gzipped_for_clients_that_accept_it() {
+        let response = lib(accept_encoding("deflate, gzip;q=0.8")).await;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/javascript");
+        assert_eq!(response.headers()[header::VARY], "Accept-Encoding");
+        let mut decoded = String::new();
+        GzDecoder::new(body_bytes(response).await.as_slice())
+            .read_to_string(&mut decoded)
+            .expect("Body should be valid gzip");
+        assert_eq!(decoded, LIB_JS);
+    }
+
+    #[tokio::test]
+    async fn javascript_is_raw_for_clients_that_dont_accept_gzip() {
+        for headers in [HeaderMap::new(), accept_encoding("br"), accept_encoding("gzip;q=0")] {
+            let response = lib(headers).await;
+            assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "text/javascript");
+            assert_eq!(response.headers()[header::VARY], "Accept-Encoding");
+            assert_eq!(body_bytes(response).await, LIB_JS.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn redacted_labels_never_reach_any_output() {
+        let source = secret_upstream().await;
+        let config = dashboards(&format!(
+            r#"
+- title: Secrets
+  graphs:
+    - title: Tokens
+      chart_type: table
+      yaxes: []
+      redact_labels: [token]
+      plots:
+        - source: {source}
+          query: up
+          config:
+            name_format: "{{token}}"
+  logs:
+    - title: Token logs
+      source: {source}
+      query: '{{job="api"}}'
+      redact_labels: [token]
+"#
+        ));
+        let query = HashMap::new();
+        let graph = graph_payload(&config, 0, 0, &query, &HeaderMap::new()).await.unwrap();
+        let logs = log_payload(&config, 0, 0, &query, &HeaderMap::new()).await.unwrap();
+        let parquet = graph_parquet(
+            State(Arc::new(config)),
+            Path((0, 0)),
+            Query(query),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let outputs = [
+            ("graph json", serde_json::to_vec(&graph).unwrap()),
+            ("log json", serde_json::to_vec(&logs).unwrap()),
+            ("parquet", body_bytes(parquet).await),
+        ];
+        for (name, output) in outputs {
+            assert!(!contains(&output, SECRET), "{} leaked the secret", name);
+            assert!(contains(&output, query::REDACTED_VALUE), "{} wasn't redacted", name);
+        }
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn filters_with_an_empty_label_or_value_are_skipped() {
+        let query = params(&[("filter-", "x"), ("filter-instance", ""), ("filter-job", "api")]);
+        assert_eq!(
+            query_to_filterset(&query),
+            Some(HashMap::from([("job", "api")]))
+        );
+        let query = params(&[("filter-", "x"), ("filter-instance", "")]);
+        assert_eq!(query_to_filterset(&query), None);
+    }
+
+    /// Dashboards with a single log stream from the source.
+    fn log_dashboards(source: &str) -> Arc<Vec<Dashboard>> {
+        Arc::new(dashboards(&format!(
+            r#"
+- title: Logs
+  logs:
+    - title: Api
+      source: {source}
+      query: '{{job="api"}}'
+"#
+        )))
+    }
+
+    #[tokio::test]
+    async fn loki_rate_limits_pass_through_with_the_retry_hint() {
+        for (retry_after, expected) in [(Some("30"), Some("30")), (None, None)] {
+            let source = serve(Router::new().route(
+                "/loki/api/v1/query_range",
+                get(move || async move {
+                    let mut headers = HeaderMap::new();
+                    if let Some(secs) = retry_after {
+                        headers.insert(header::RETRY_AFTER, HeaderValue::from_static(secs));
+                    }
+                    (StatusCode::TOO_MANY_REQUESTS, headers, "too many outstanding requests")
+                }),
+            ))
+            .await;
+            let response = loki_query(
+                State(log_dashboards(&source)),
+                Path((0, 0)),
+                Query(HashMap::new()),
+                HeaderMap::new(),
+            )
+            .await
+            .into_response();
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(
+                response
+                    .headers()
+                    .get(header::RETRY_AFTER)
+                    .map(|v| v.to_str().unwrap()),
+                expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn log_streams_are_grouped_unless_merged() {
+        const STREAMS: &str = r#"{"status":"success","data":{"resultType":"streams","result":[{"stream":{"job":"api"},"values":[["3000000000","api 3"],["1000000000","api 1"]]},{"stream":{"job":"db"},"values":[["2000000000","db 2"]]}]}}"#;
+        let source = serve(Router::new().route(
+            "/loki/api/v1/query_range",
+            get(|| async { ([(header::CONTENT_TYPE, "application/json")], STREAMS) }),
+        ))
+        .await;
+        let config = dashboards(&format!(
+            r#"
+- title: Logs
+  logs:
+    - title: Grouped
+      source: {source}
+      query: '{{job=~".+"}}'
+    - title: Merged
+      source: {source}
+      query: '{{job=~".+"}}'
+      merge_streams: true
+"#
+        ));
+        let query = HashMap::new();
+        let lines = |payload| match payload {
+            QueryPayload::Logs(LogsPayload { lines, .. }) => lines,
+            _ => panic!("Expected logs"),
+        };
+        match lines(log_payload(&config, 0, 0, &query, &HeaderMap::new()).await.unwrap()) {
+            LogQueryResult::Stream(streams) => {
+                let counts = streams
+                    .iter()
+                    .map(|(labels, lines)| (labels["job"].as_str(), lines.len()))
+                    .collect::<Vec<_>>();
+                assert_eq!(counts, vec![("api", 2), ("db", 1)]);
+            }
+            _ => panic!("Expected lines grouped by stream"),
+        }
+        match lines(log_payload(&config, 0, 1, &query, &HeaderMap::new()).await.unwrap()) {
+            LogQueryResult::MergedStream(merged) => {
+                let jobs = merged
+                    .iter()
+                    .map(|(labels, _)| labels["job"].as_str())
+                    .collect::<Vec<_>>();
+                assert_eq!(jobs, vec!["api", "db", "api"]);
+            }
+            _ => panic!("Expected merged lines"),
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_upstreams_time_out_with_a_504() {
+        let slow = || async {
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            StatusCode::NO_CONTENT
+        };
+        let source = serve(
+            Router::new()
+                .route("/api/v1/query_range", get(slow))
+                .route("/loki/api/v1/query_range", get(slow)),
+        )
+        .await;
+        let config = Arc::new(dashboards(&format!(
+            r#"
+- title: Slow
+  timeout: 200ms
+  graphs:
+    - title: Graph
+      yaxes: []
+      plots:
+        - source: {source}
+          query: up
+          config: {{}}
+  logs:
+    - title: Logs
+      source: {source}
+      query: '{{job="api"}}'
+"#
+        )));
+        let (graph, logs) = futures::join!(
+            graph_query(
+                State(config.clone()),
+                Path((0, 0)),
+                Query(HashMap::new()),
+                HeaderMap::new(),
+            ),
+            loki_query(
+                State(config.clone()),
+                Path((0, 0)),
+                Query(HashMap::new()),
+                HeaderMap::new(),
+            ),
+        );
+        assert_eq!(graph.into_response().status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(logs.into_response().status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn poll_wait_is_the_rest_of_the_interval() {
+        let now = 1_700_000_000;
+        assert_eq!(poll_wait(now - 10, 30, now), 20);
+        assert_eq!(poll_wait(now, 30, now), 30);
+        // Already past the interval.
+        assert_eq!(poll_wait(now - 60, 30, now), 0);
+        assert_eq!(poll_wait(now, -30, now), 0);
+        // Capped no matter how far out the client asks for.
+        assert_eq!(poll_wait(now + 3600, 30, now), MAX_POLL_WAIT_SECONDS);
+        assert_eq!(poll_wait(now, 3600, now), MAX_POLL_WAIT_SECONDS);
+        // Client supplied extremes saturate instead of overflowing.
+        assert_eq!(poll_wait(i64::MAX, i64::MAX, now), MAX_POLL_WAIT_SECONDS);
+        assert_eq!(poll_wait(i64::MIN, i64::MIN, now), 0);
+        assert_eq!(poll_wait(0, 0, i64::MIN), MAX_POLL_WAIT_SECONDS);
+    }
+
+    #[tokio::test]
+    async fn poll_for_a_missing_graph_fails_without_waiting() {
+        let config = log_dashboards("http://localhost");
+        let poll = graph_poll(
+            State(config),
+            Path((0, 0)),
+            Query(params(&[("interval", "60")])),
+            HeaderMap::new(),
+        );
+        let response = tokio::time::timeout(std::time::Duration::from_secs(5), poll)
+            .await
+            .expect("Should fail before the poll wait")
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
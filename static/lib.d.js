@@ -17,6 +17,24 @@
  * @type {object}
  * @property {Array=} Series
  * @property {Array=} Scalar
+ * @property {Array=} Histogram - Native histogram samples. See HistogramPoint
+ */
+
+/**
+ * @typedef HistogramBucket
+ * @type {object}
+ * @property {number} lower
+ * @property {number} upper
+ * @property {number} count
+ */
+
+/**
+ * @typedef HistogramPoint
+ * @type {object}
+ * @property {number} timestamp
+ * @property {number} count
+ * @property {number} sum
+ * @property {Array<HistogramBucket>} buckets - Only the buckets with observations
  */
 
 /**
@@ -24,6 +42,26 @@
  * @type {object}
  * @property {Array=} StreamInstant - Timestamps are in seconds
  * @property {Array=} Stream - Timestamps are in nanoseconds
+ * @property {Array=} MergedStream - Lines from every stream sorted newest first. Timestamps are in nanoseconds
+ */
+
+/**
+ * @typedef TableRow
+ * @type {object}
+ * @property {Object<string, string>} labels
+ * @property {number} last
+ * @property {number} min
+ * @property {number} max
+ * @property {number} avg
+ */
+
+/**
+ * @typedef SloStatus
+ * @type {object}
+ * @property {number} error_ratio
+ * @property {number} burn_rate - 1 uses up exactly the budget over the window
+ * @property {number} budget_remaining - Fraction of the budget left
+ * @property {string=} color
  */
 
 /**
@@ -32,11 +70,34 @@
  * @property {object} yaxes
  * @property {?string} legend_orientation
  * @property {Array<PlotList>} plots
+ * @property {Array<TableRow>=} table
+ * @property {Array<string>=} warnings
+ * @property {SloStatus=} slo
+ * @property {boolean=} fallback - The plots are an instant query's value because the range was empty
+ * @property {Array<PlotList>=} detail - High resolution plots for the end of the span
+ */
+
+/**
+ * @typedef LogsData
+ * @type {object}
+ * @property {LogLineList} lines
+ * @property {boolean=} truncated - The lines probably got cut off at the limit
+ * @property {number=} limit
  */
 
 /**
  * @typedef QueryPayload
- * @type {{Metrics: QueryData, Logs: {lines: LogLineList}}}
+ * @type {{Metrics: QueryData, Logs: LogsData}}
+ */
+
+/**
+ * Sent instead of a QueryPayload for `?v=2` or `Accept: application/vnd.heracles.v2+json`.
+ * @typedef QueryPayloadV2
+ * @type {object}
+ * @property {number} version
+ * @property {number} generated_at - Unix timestamp in seconds
+ * @property {?number} step_seconds - The step the range queries actually used
+ * @property {QueryPayload} data
  */
 
 /** 
@@ -69,10 +130,12 @@
  * @type {object}
  * @property {string=} name
  * @property {string=} fill
+ * @property {number=} opacity
  * @property type {string}
  * @property {string=} mode
  * @property {Array} x
  * @property {Array} y
+ * @property {Array=} z - Heatmaps only
  * @property {string=} xaxis 
  * @property {string=} yaxis 
 */
@@ -86,7 +149,15 @@
  * @typedef PlotConfig
  * @type {object}
  * @property {string=} name_format
+ * @property {?string=} name - Rendered on the server from a `{label}` name_format
  * @property {string=} yaxis
+ * @property {boolean=} histogram - The plot is native histogram samples
  * @property {("tonexty"|"tozeroy"|"tonextx"|"tozerox"|"toself"|"tonext")=} fill
  */
 
+/**
+ * @typedef PlaylistSlide
+ * @type {object}
+ * @property {string} uri - The dashboard's ui fragment
+ * @property {number} dwell_seconds
+ */
@@ -67,6 +67,10 @@ function ansiToHtml(line) {
  * @return string
  */
 function formatName(config, labels) {
+    // The server already rendered `{label}` formats.
+    if (config.name != null) {
+        return config.name;
+    }
     var name = "";
     const formatter = config.name_format
     if (formatter) {
@@ -110,6 +114,14 @@ class ElementConfig {
     step_duration;
     /** @type {?string} */
     d3TickFormat = "~s";
+    /** @type {boolean} */
+    pauseWhenHidden = true;
+    /** @type {?string} - Module whose default export processes payloads before rendering. */
+    payloadHook = null;
+    /** @type {?QueryPayload} - Inlined by the server so the first render doesn't need a fetch. */
+    payload = null;
+    /** @type {number} - Don't poll before this time in milliseconds. Set by rate limited responses. */
+    backoffUntil = 0;
     /** @type {?HTMLDivElement} */
     targetNode = null;
     /** @type {?HTMLElement} */
@@ -145,6 +157,21 @@ class ElementConfig {
         this.step_duration = element.getAttribute('step-duration') || null;
         this.d3TickFormat = element.getAttribute('d3-tick-format') || this.d3TickFormat;
         this.allowUriFilters = Boolean(element.getAttribute('allow-uri-filters'));
+        this.pauseWhenHidden = element.getAttribute('pause-when-hidden') !== "false";
+        this.payloadHook = element.getAttribute('payload-hook');
+        const payload = element.getAttribute('payload');
+        if (payload) {
+            this.payload = JSON.parse(payload);
+        }
+    }
+
+    /**
+     * Whether polling should be skipped right now.
+     *
+     * @returns {boolean}
+     */
+    isPaused() {
+        return (this.pauseWhenHidden && document.hidden) || Date.now() < this.backoffUntil;
     }
 
     stopInterval() {
@@ -183,21 +210,56 @@ class ElementConfig {
             }
         }
         if (uriParts) {
-            return this.uri + "?" + uriParts.join('&');
+            // The server may have already baked filters from a saved view into the uri.
+            const separator = this.uri.includes("?") ? "&" : "?";
+            return this.uri + separator + uriParts.join('&');
         } else {
             return this.uri;
         }
     }
 
     /**
-     * Returns the data from an api call.
+     * Returns the data from an api call after the dashboard's payload hook has processed it.
+     *
+     * A dashboard's custom_js module is the payload hook. Its default export gets called with
+     * the payload and the element before every render and returns the payload to render.
+     *
+     *     export default function(payload, element) { return payload; }
      *
      * @return {Promise<QueryPayload>}
      */
     async fetchData() {
+        const data = await this.fetchPayload();
+        if (!this.payloadHook) {
+            return data;
+        }
+        const hook = (await import(this.payloadHook)).default;
+        return (hook && await hook(data, this.#container)) || data;
+    }
+
+    /**
+     * @return {Promise<QueryPayload>}
+     */
+    async fetchPayload() {
+        if (this.payload) {
+            // Only the first render uses the inlined payload. Polls fetch fresh data.
+            const payload = this.payload;
+            this.payload = null;
+            return payload;
+        }
         // TODO(zaphar): Can we do some massaging on these
         // to get the full set of labels and possible values?
         const response = await fetch(this.getUri());
+        if (response.status == 429) {
+            // The source is rate limiting us so back off for as long as it asked.
+            const retryAfter = Number(response.headers.get("Retry-After")) || this.pollSeconds;
+            this.backoffUntil = Date.now() + 1000 * retryAfter;
+            throw new Error(`Rate limited. Retrying after ${retryAfter} seconds`);
+        }
+        if (!response.ok) {
+            const body = await response.json().catch(() => ({}));
+            throw new Error(`Query failed: ${response.status} ${body.error || ""}`);
+        }
         const data = await response.json();
         return data;
     }
@@ -326,6 +388,9 @@ class ElementConfig {
             case 'allow-uri-filters':
                 this.allowUriFilters = Boolean(newValue);
                 break;
+            case 'pause-when-hidden':
+                this.pauseWhenHidden = newValue !== "false";
+                break;
             default: // do nothing;
                 break;
         }
@@ -392,7 +457,11 @@ export class LogPlot extends HTMLElement {
                 self.#config.buildFilterMenu(this);
             }
             self.updateGraph(data).then(() => {
-                self.#config.intervalId = setInterval(() => self.updateGraph(), 1000 * self.#config.pollSeconds);
+                self.#config.intervalId = setInterval(() => {
+                    if (!self.#config.isPaused()) {
+                        self.updateGraph();
+                    }
+                }, 1000 * self.#config.pollSeconds);
             });
         });
     }
@@ -407,6 +476,11 @@ export class LogPlot extends HTMLElement {
                 this.#config.populateFilterData(labels);
             }
         }
+        if (graph.MergedStream) {
+            for (const pair of graph.MergedStream) {
+                this.#config.populateFilterData(pair[0]);
+            }
+        }
         if (graph.StreamInstant) {
             // TODO(zaphar): Handle this?
         }
@@ -437,7 +511,7 @@ export class LogPlot extends HTMLElement {
             for (const line of lines) {
                 // For streams the timestamps are in nanoseconds
                 let timestamp = new Date(line.timestamp / 1000000);
-                dateColumn.push(timestamp.toISOString());
+                dateColumn.push(line.time_display || timestamp.toISOString());
                 configColumn.push(labelsName);
                 logColumn.push(ansiToHtml(line.line));
             }
@@ -458,11 +532,26 @@ export class LogPlot extends HTMLElement {
         if (graph.Metrics) {
             // FIXME(zaphar): Log an Error;
         } else if (graph.Logs) {
+            this.updateLogsWarning(graph.Logs);
             this.updateLogsView(graph.Logs.lines);
         } else {
         }
     }
 
+    /**
+     * Warn when the lines probably got cut off at the limit.
+     *
+     * @param {LogsData} logs
+     */
+    updateLogsWarning(logs) {
+        const warningSlot = this.closest(".panel")?.querySelector(".panel-warning");
+        if (warningSlot) {
+            warningSlot.textContent = logs.truncated
+                ? `Showing only the newest ${logs.limit} lines. Narrow the query or the span to see the rest.`
+                : "";
+        }
+    }
+
     /**
      * Update the logs view with new data.
      *
@@ -485,7 +574,10 @@ export class LogPlot extends HTMLElement {
             }
         };
         var traces = [];
-        if (logLineList.Stream) {
+        // Merged streams are one line per stream so they render in their time order.
+        const stream = logLineList.Stream
+            || logLineList.MergedStream?.map(([labels, line]) => [labels, [line]]);
+        if (stream) {
             // TODO(jwall): It's possible that this should actually be a separate custom
             // element.
             const trace = /** @type TableTrace  */ ({
@@ -503,7 +595,7 @@ export class LogPlot extends HTMLElement {
                     fill: { color: layout.plot_bgcolor }
                 },
             });
-            const columns = this.buildStreamPlot(logLineList.Stream);
+            const columns = this.buildStreamPlot(stream);
             trace.cells.values.push(columns.dates);
             trace.cells.values.push(columns.config);
             trace.cells.values.push(columns.lines);
@@ -528,6 +620,10 @@ LogPlot.registerElement();
 export class GraphPlot extends HTMLElement {
     /** @type {?ElementConfig} */
     #config;
+    /** @type {string} */
+    #sortColumn = "last";
+    /** @type {boolean} */
+    #sortDescending = true;
 
     constructor() {
         super();
@@ -550,6 +646,12 @@ export class GraphPlot extends HTMLElement {
 
     connectedCallback() {
         this.#config.connectedHandler(this);
+        // @ts-ignore
+        if (typeof Plotly === "undefined") {
+            // Plotly didn't load so show the server rendered fallback instead.
+            this.parentElement?.querySelector(".plot-fallback")?.removeAttribute("hidden");
+            return;
+        }
         this.reset(true);
     }
 
@@ -572,7 +674,11 @@ export class GraphPlot extends HTMLElement {
                 self.#config.buildFilterMenu(this);
             }
             self.updateGraph(data).then(() => {
-                self.#config.intervalId = setInterval(() => self.updateGraph(), 1000 * self.#config.pollSeconds);
+                self.#config.intervalId = setInterval(() => {
+                    if (!self.#config.isPaused()) {
+                        self.updateGraph();
+                    }
+                }, 1000 * self.#config.pollSeconds);
             });
         });
     }
@@ -602,13 +708,20 @@ export class GraphPlot extends HTMLElement {
                     this.#config.populateFilterData(labels);
                 }
             }
+            if (subplot.Histogram) {
+                for (const triple of subplot.Histogram) {
+                    const labels = triple[0];
+                    this.#config.populateFilterData(labels);
+                }
+            }
         }
     }
 
     /**
      * @param {any} triple
+     * @param {boolean=} markers
      */
-    buildSeriesPlot(triple) {
+    buildSeriesPlot(triple, markers) {
         const labels = /** @type {Map<String, String>} */(triple[0]);
         for (var label in labels) {
             var show = this.#config.filteredLabelSets[label];
@@ -622,7 +735,7 @@ export class GraphPlot extends HTMLElement {
         const series = triple[2];
         const trace = /** @type GraphTrace */({
             type: "scatter",
-            mode: "lines+text",
+            mode: markers ? "markers" : "lines+text",
             x: [],
             y: [],
             // We always share the x axis for timeseries graphs.
@@ -642,6 +755,42 @@ export class GraphPlot extends HTMLElement {
         return trace;
     }
 
+    /**
+     * Draws native histogram samples as a heatmap with a row per bucket upper bound and a
+     * column per timestamp.
+     *
+     * @param {any} triple
+     */
+    buildHistogramPlot(triple) {
+        const labels = /** @type {Map<String, String>} */(triple[0]);
+        for (var label in labels) {
+            var show = this.#config.filteredLabelSets[label];
+            if (show && !show.includes(labels[label])) {
+                return null;
+            }
+        }
+        const config = /** @type {PlotConfig} */(triple[1]);
+        const points = /** @type {Array<HistogramPoint>} */(triple[2]);
+        const bounds = [...new Set(points.flatMap((point) => point.buckets.map((b) => b.upper)))]
+            .sort((a, b) => a - b);
+        // https://plotly.com/javascript/reference/heatmap/
+        const trace = /** @type GraphTrace */({
+            type: "heatmap",
+            x: points.map((point) => new Date(point.timestamp * 1000)),
+            y: bounds,
+            // Buckets without observations are left out of a sample so they count as zero.
+            z: bounds.map((upper) => points.map((point) => {
+                const bucket = point.buckets.find((b) => b.upper == upper);
+                return bucket ? bucket.count : 0;
+            })),
+            xaxis: "x",
+            yaxis: config.yaxis || "y",
+        });
+        var name = formatName(config, labels);
+        if (name) { trace.name = name; }
+        return trace;
+    }
+
     /**
      * @param {any} triple
      */
@@ -663,11 +812,65 @@ export class GraphPlot extends HTMLElement {
         });
         var name = formatName(config, labels);
         if (name) { trace.name = name; }
+        if (series.color) { trace.marker = { color: series.color }; }
         trace.y.push(series.value);
         trace.x.push(trace.name);
         return trace;
     }
 
+    /**
+     * Draws a scalar as a horizontal line across the time range of the series it overlays.
+     *
+     * @param {any} triple
+     * @param {Array<Date>} xRange
+     */
+    buildScalarLinePlot(triple, xRange) {
+        const trace = this.buildScalarPlot(triple);
+        if (!trace) {
+            return null;
+        }
+        const value = trace.y[0];
+        trace.type = "scatter";
+        trace.mode = "lines";
+        trace.xaxis = "x";
+        trace.x = xRange;
+        trace.y = xRange.map(() => value);
+        if (trace.marker) {
+            trace.line = { color: trace.marker.color };
+        }
+        return trace;
+    }
+
+    /**
+     * @param {any} triple
+     * @param {?number} min
+     * @param {?number} max
+     */
+    buildGaugePlot(triple, min, max) {
+        const labels = /** @type {Map<String,String>} */(triple[0]);
+        for (var label in labels) {
+            var show = this.#config.filteredLabelSets[label];
+            if (show && !show.includes(labels[label])) {
+                return null;
+            }
+        }
+        const config = /** @type {PlotConfig} */(triple[1]);
+        const series = triple[2];
+        // https://plotly.com/javascript/reference/indicator/
+        const trace = /** @type GraphTrace  */({
+            type: "indicator",
+            mode: "gauge+number",
+            value: series.value,
+            gauge: {
+                axis: { range: [min ?? 0, max ?? Math.max(series.value, 0)] },
+                bar: series.color ? { color: series.color } : undefined,
+            },
+        });
+        var name = formatName(config, labels);
+        if (name) { trace.title = { text: name }; }
+        return trace;
+    }
+
     /**
      * Update the graph with new data.
      *
@@ -692,6 +895,22 @@ export class GraphPlot extends HTMLElement {
      * @param {?QueryData=} graph
      */
     updateMetricsGraph(graph) {
+        const warningSlot = this.closest(".panel")?.querySelector(".panel-warning");
+        if (warningSlot) {
+            const warnings = [...(graph.warnings || [])];
+            if (graph.fallback) {
+                warnings.push("No data in range. Showing the current value.");
+            }
+            warningSlot.textContent = warnings.join("; ");
+        }
+        if (graph.table) {
+            this.renderTable(graph.table);
+            return;
+        }
+        if (graph.slo) {
+            this.renderSlo(graph.slo);
+            return;
+        }
         var data = graph.plots;
         var yaxes = graph.yaxes;
         var layout = {
@@ -719,13 +938,27 @@ export class GraphPlot extends HTMLElement {
             layout[nextYaxis()] = yaxis;
         }
         var traces = /** @type {Array<PlotTrace>} */ ([]);
+        // Scalar plots mixed into a timeseries graph are drawn as lines across its time range.
+        var xRange = null;
+        for (const subplot of data) {
+            for (const triple of (subplot.Series || []).concat(subplot.Histogram || [])) {
+                for (const point of triple[2]) {
+                    xRange = xRange
+                        ? [Math.min(xRange[0], point.timestamp), Math.max(xRange[1], point.timestamp)]
+                        : [point.timestamp, point.timestamp];
+                }
+            }
+        }
+        if (xRange) {
+            xRange = xRange.map((timestamp) => new Date(timestamp * 1000));
+        }
         for (var subplot_idx in data) {
             const subplot = data[subplot_idx];
             var nextYaxis = yaxisNameGenerator();
             if (subplot.Series) {
                 // https://plotly.com/javascript/reference/scatter/
                 for (const triple of subplot.Series) {
-                    const trace = this.buildSeriesPlot(triple);
+                    const trace = this.buildSeriesPlot(triple, graph.markers);
                     if (trace) {
                         traces.push(trace);
                     }
@@ -733,21 +966,185 @@ export class GraphPlot extends HTMLElement {
             } else if (subplot.Scalar) {
                 // https://plotly.com/javascript/reference/bar/
                 for (const triple of subplot.Scalar) {
-                    const trace = this.buildScalarPlot(triple);
+                    const trace = graph.scalar_display == "gauge"
+                        ? this.buildGaugePlot(triple, graph.gauge_min, graph.gauge_max)
+                        : xRange
+                            ? this.buildScalarLinePlot(triple, xRange)
+                            : this.buildScalarPlot(triple);
+                    if (trace) {
+                        traces.push(trace);
+                    }
+                }
+            } else if (subplot.Histogram) {
+                for (const triple of subplot.Histogram) {
+                    const trace = this.buildHistogramPlot(triple);
                     if (trace) {
                         traces.push(trace);
                     }
                 }
             }
         }
+        if (graph.detail) {
+            // The overview is drawn faintly behind the detailed recent window.
+            for (const trace of traces) {
+                trace.opacity = 0.4;
+            }
+            for (const subplot of graph.detail) {
+                for (const triple of subplot.Series || []) {
+                    const trace = this.buildSeriesPlot(triple, graph.markers);
+                    if (trace) {
+                        trace.name = `${trace.name || ""} (detail)`;
+                        traces.push(trace);
+                    }
+                }
+            }
+        }
+        // Gauges don't share axes so lay them out side by side.
+        const gauges = traces.filter((trace) => trace.type == "indicator");
+        gauges.forEach((trace, idx) => {
+            trace.domain = { x: [idx / gauges.length, (idx + 1) / gauges.length], y: [0, 1] };
+        });
+        // Annotations render as labeled vertical lines spanning the whole plot.
+        // https://plotly.com/javascript/shapes/
+        if (graph.annotations && graph.annotations.length) {
+            layout.shapes = [];
+            layout.annotations = [];
+            for (const annotation of graph.annotations) {
+                const x = new Date(annotation.timestamp);
+                const color = annotation.color || getCssVariableValue('--accent-color').trim();
+                layout.shapes.push({
+                    type: "line", x0: x, x1: x, yref: "paper", y0: 0, y1: 1,
+                    line: { color: color, dash: "dot" },
+                });
+                layout.annotations.push({
+                    x: x, yref: "paper", y: 1, text: annotation.label, showarrow: false,
+                    font: { color: color },
+                });
+            }
+        }
         // https://plotly.com/javascript/plotlyjs-function-reference/#plotlyreact
         // @ts-ignore
         Plotly.react(this.#config.getTargetNode(), traces, layout, null);
     }
+
+    /**
+     * Renders an error budget as burn rate and budget remaining indicators.
+     *
+     * @param {SloStatus} slo
+     */
+    renderSlo(slo) {
+        const color = slo.color ? { color: slo.color } : undefined;
+        // https://plotly.com/javascript/reference/indicator/
+        const traces = [
+            {
+                type: "indicator", mode: "number", value: slo.burn_rate,
+                title: { text: "burn rate" }, number: { font: color, valueformat: ".2f" },
+                domain: { x: [0, 0.5], y: [0, 1] },
+            },
+            {
+                type: "indicator", mode: "number", value: slo.budget_remaining * 100,
+                title: { text: "budget remaining" }, number: { font: color, suffix: "%", valueformat: ".1f" },
+                domain: { x: [0.5, 1], y: [0, 1] },
+            },
+        ];
+        const layout = {
+            paper_bgcolor: getCssVariableValue('--paper-background-color').trim(),
+            font: { color: getCssVariableValue('--text-color').trim() },
+        };
+        // @ts-ignore
+        Plotly.react(this.#config.getTargetNode(), traces, layout, null);
+    }
+
+    /**
+     * Renders the rows of a table chart as a table sortable by clicking the column headers.
+     *
+     * @param {Array<TableRow>} rows
+     */
+    renderTable(rows) {
+        const self = this;
+        rows = rows.filter((row) => {
+            for (var label in row.labels) {
+                var show = self.#config.filteredLabelSets[label];
+                if (show && !show.includes(row.labels[label])) {
+                    return false;
+                }
+            }
+            return true;
+        });
+        const labelColumns = [...new Set(rows.flatMap((row) => Object.keys(row.labels)))].sort();
+        const valueColumns = ["last", "min", "max", "avg"];
+        /** @param {TableRow} row @param {string} column */
+        const cell = (row, column) => valueColumns.includes(column) ? row[column] : (row.labels[column] ?? "");
+        const column = self.#sortColumn;
+        rows.sort((a, b) => {
+            const left = cell(a, column);
+            const right = cell(b, column);
+            const order = left < right ? -1 : (left > right ? 1 : 0);
+            return self.#sortDescending ? -order : order;
+        });
+        const table = document.createElement("table");
+        table.className = "graph-table";
+        const header = table.createTHead().insertRow();
+        for (const name of [...labelColumns, ...valueColumns]) {
+            const th = header.appendChild(document.createElement("th"));
+            th.textContent = name == column ? `${name} ${self.#sortDescending ? "▼" : "▲"}` : name;
+            th.onclick = function(_evt) {
+                self.#sortDescending = name == self.#sortColumn ? !self.#sortDescending : true;
+                self.#sortColumn = name;
+                self.renderTable(rows);
+            };
+        }
+        const body = table.createTBody();
+        for (const row of rows) {
+            const tr = body.insertRow();
+            for (const name of labelColumns) {
+                tr.insertCell().textContent = row.labels[name] ?? "";
+            }
+            for (const name of valueColumns) {
+                tr.insertCell().textContent = row[name].toLocaleString();
+            }
+        }
+        this.#config.getTargetNode().replaceChildren(table);
+    }
 }
 
 GraphPlot.registerElement();
 
+/**
+ * Finds the error slot for an element that triggered an htmx request.
+ * @param {HTMLElement} elt
+ * @returns {?HTMLElement}
+ */
+function errorSlotFor(elt) {
+    return elt.closest(".panel")?.querySelector(".panel-error") || document.getElementById("dashboard-error");
+}
+
+// htmx doesn't swap error responses so the current content stays in place. Show the error in
+// the panel's error slot instead of failing silently.
+document.addEventListener("htmx:responseError", (evt) => {
+    const slot = errorSlotFor(evt.detail.elt);
+    if (slot) {
+        slot.textContent = `Failed to load: ${evt.detail.xhr.status} ${evt.detail.xhr.responseText}`;
+    }
+});
+
+document.addEventListener("htmx:beforeRequest", (evt) => {
+    const slot = errorSlotFor(evt.detail.elt);
+    if (slot) {
+        slot.textContent = "";
+    }
+});
+
+// Mark the selected log tab. The tab's panel is swapped in by htmx.
+document.addEventListener("htmx:beforeRequest", (evt) => {
+    const tab = evt.detail.elt;
+    if (tab.classList.contains("log-tab")) {
+        for (const sibling of tab.parentElement.querySelectorAll(".log-tab")) {
+            sibling.classList.toggle("active", sibling == tab);
+        }
+    }
+});
+
 /** Custom Element for selecting a timespan for the dashboard. */
 export class SpanSelector extends HTMLElement {
     /** @type {HTMLElement} */
@@ -816,3 +1213,59 @@ export class SpanSelector extends HTMLElement {
 
 SpanSelector.registerElement();
 
+/**
+ * Rotates the dashboards of a playlist into its target element.
+ *
+ * @extends HTMLElement
+ */
+export class PlaylistRotator extends HTMLElement {
+    /** @type {Array<PlaylistSlide>} */
+    #slides = [];
+    /** @type {number} */
+    #current = 0;
+    /** @type {?number} */
+    #timeoutId = null;
+
+    connectedCallback() {
+        this.#slides = JSON.parse(this.getAttribute('slides') || "[]");
+        this.#current = 0;
+        this.show();
+    }
+
+    disconnectedCallback() {
+        if (this.#timeoutId) {
+            clearTimeout(this.#timeoutId);
+            this.#timeoutId = null;
+        }
+    }
+
+    /** Shows the current slide and schedules the next one. */
+    show() {
+        const slide = this.#slides[this.#current];
+        if (!slide) {
+            return;
+        }
+        // @ts-ignore
+        htmx.ajax('GET', slide.uri, { target: this.getAttribute('target') });
+        const last = this.#current == this.#slides.length - 1;
+        if (last && this.getAttribute('loop') !== "true") {
+            return;
+        }
+        this.#timeoutId = setTimeout(() => {
+            this.#current = (this.#current + 1) % this.#slides.length;
+            this.show();
+        }, slide.dwell_seconds * 1000);
+    }
+
+    static elementName = "playlist-rotator";
+
+    /** Register the element if it doesn't exist */
+    static registerElement() {
+        if (!customElements.get(PlaylistRotator.elementName)) {
+            customElements.define(PlaylistRotator.elementName, PlaylistRotator);
+        }
+    }
+}
+
+PlaylistRotator.registerElement();
+